@@ -0,0 +1,147 @@
+//! The chat overlay - recent messages drawn over the game, and the line being
+//! typed.
+//!
+//! Press enter to start typing, and enter again to send. Messages go to the
+//! whole room, unless they start with `/t` to send to our team or
+//! `/w <client id>` to whisper to one player.
+
+use std::collections::VecDeque;
+use nalgebra::Vector2;
+use common::net::{ChatScope, ChatSendPacket, ChatBroadcastPacket, MAX_CHAT_LEN};
+use renderer::RendererController;
+
+/// The most messages shown at once.
+const MAX_LINES : usize = 8;
+/// How long a message is shown for, in nanoseconds.
+const LINE_LIFETIME_NS : u64 = 10_000_000_000;
+/// The size of a font pixel.
+const TEXT_SCALE : f32 = 2.0;
+/// The distance between the tops of lines of text.
+const LINE_HEIGHT : f32 = 9.0 * TEXT_SCALE;
+/// The top left of the overlay.
+const OVERLAY_POS : [f32; 2] = [8.0, 8.0];
+
+const ROOM_COLOR : [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const TEAM_COLOR : [f32; 4] = [0.4, 0.8, 1.0, 1.0];
+const WHISPER_COLOR : [f32; 4] = [1.0, 0.5, 1.0, 1.0];
+const NOTICE_COLOR : [f32; 4] = [1.0, 1.0, 0.3, 1.0];
+
+/// A line shown in the overlay.
+struct Line {
+  text: String,
+  col: [f32; 4],
+  /// When the line was added, in ns - unspecified epoch
+  time: u64,
+}
+
+pub struct ChatOverlay {
+  /// Recent lines, oldest first.
+  lines: VecDeque<Line>,
+  /// The message being typed, or `None` if we aren't typing.
+  input: Option<String>,
+}
+
+impl ChatOverlay {
+  pub fn new() -> ChatOverlay {
+    ChatOverlay { lines: VecDeque::with_capacity(MAX_LINES), input: None }
+  }
+
+  /// Whether a message is being typed, so key presses shouldn't control the
+  /// game.
+  pub fn is_typing(&self) -> bool {
+    self.input.is_some()
+  }
+
+  /// Show a message relayed by the server.
+  /// # Params
+  /// * `message` - The message
+  /// * `now` - The current time in ns
+  pub fn push_message(&mut self, message: &ChatBroadcastPacket, now: u64) {
+    let (prefix, col) = match message.scope {
+      ChatScope::Room => ("", ROOM_COLOR),
+      ChatScope::Team => ("[team] ", TEAM_COLOR),
+      ChatScope::Whisper(_) => ("[whisper] ", WHISPER_COLOR),
+    };
+    self.push_line(format!("{}{}: {}", prefix, message.sender_name, message.text), col, now);
+  }
+
+  /// Show a notice which isn't from a player, such as a rejected message.
+  pub fn push_notice(&mut self, text: &str, now: u64) {
+    self.push_line(text.to_owned(), NOTICE_COLOR, now);
+  }
+
+  /// Handle a typed character.
+  /// # Returns
+  /// A message to send to the server, if one was finished.
+  pub fn handle_char(&mut self, c: char) -> Option<ChatSendPacket> {
+    let input = match self.input {
+      Some(ref mut input) => input,
+      None => {
+        if c == '\r' || c == '\n' { self.input = Some(String::new()); }
+        return None;
+      }
+    };
+    match c {
+      '\r' | '\n' => {
+        let text = self.input.take().unwrap();
+        parse_input(&text)
+      }
+      // Escape
+      '\u{1b}' => {
+        self.input = None;
+        None
+      }
+      // Backspace
+      '\u{8}' => {
+        input.pop();
+        None
+      }
+      c if c.is_control() => None,
+      c => {
+        if input.chars().count() < MAX_CHAT_LEN { input.push(c); }
+        None
+      }
+    }
+  }
+
+  /// Draw the overlay. Lines older than their lifetime are removed.
+  /// # Params
+  /// * `r` - The renderer controller to draw with
+  /// * `now` - The current time in ns
+  pub fn draw(&mut self, r: &RendererController, now: u64) {
+    while self.lines.front().is_some_and(|l| now.saturating_sub(l.time) > LINE_LIFETIME_NS) {
+      self.lines.pop_front();
+    }
+    let mut y = OVERLAY_POS[1];
+    for line in &self.lines {
+      r.text(Vector2::new(OVERLAY_POS[0], y), &line.text, TEXT_SCALE, &line.col);
+      y += LINE_HEIGHT;
+    }
+    if let Some(ref input) = self.input {
+      r.text(Vector2::new(OVERLAY_POS[0], y), &format!("> {}_", input), TEXT_SCALE, &ROOM_COLOR);
+    }
+  }
+
+  fn push_line(&mut self, text: String, col: [f32; 4], now: u64) {
+    if self.lines.len() == MAX_LINES { self.lines.pop_front(); }
+    self.lines.push_back(Line { text, col, time: now });
+  }
+}
+
+/// Work out the scope of a typed message from its prefix.
+/// # Returns
+/// The message to send, or `None` if there's nothing to send.
+fn parse_input(input: &str) -> Option<ChatSendPacket> {
+  let input = input.trim();
+  let (scope, text) = if let Some(text) = input.strip_prefix("/t ") {
+    (ChatScope::Team, text)
+  } else if let Some(rest) = input.strip_prefix("/w ") {
+    let mut parts = rest.trim_start().splitn(2, ' ');
+    let id = parts.next()?.parse().ok()?;
+    (ChatScope::Whisper(id), parts.next()?)
+  } else {
+    (ChatScope::Room, input)
+  };
+  if text.trim().is_empty() { return None; }
+  Some(ChatSendPacket { scope, text: text.to_owned() })
+}
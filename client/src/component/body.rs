@@ -1,27 +1,17 @@
 use specs;
-
-pub const BODY_GRAVITY : u32 = 1;
+use common::physics::{Aabb, Body};
 
 /// A component representing a physical body in the world. Should be coupled
-/// with an AABB component.
-pub struct CompBody {
-  /// Acceleration vector
-  pub acc: [f32; 2],
-  /// Velocity vector
-  pub vel: [f32; 2],
-  /// Mass in KG
-  pub mass: f32,
-  /// Bitflags indicating properties of this body
-  /// * BIT 0 - Gravity. 1 for this body to be affected by gravity, 0 for not.
-  pub flags: u32,
-}
+/// with an AABB component. Bodies are simulated by `common::physics`, the
+/// same as on the server.
+pub struct CompBody(pub Body);
 
 impl specs::Component for CompBody {
   type Storage = specs::VecStorage<CompBody>;
 }
 
-/// AABB component - X, Y, W, H format.
-pub struct CompAABB(pub [f32; 4]);
+/// AABB component, in the same X, Y, W, H format as `common::physics`.
+pub struct CompAABB(pub Aabb);
 impl specs::Component for CompAABB {
   type Storage = specs::VecStorage<CompAABB>;
 }
@@ -4,4 +4,4 @@ mod body;
 pub use self::color::CompColor;
 pub use self::body::CompBody;
 pub use self::body::CompAABB;
-pub use self::body::BODY_GRAVITY;
+pub use common::physics::{Body, BODY_GRAVITY};
@@ -11,11 +11,14 @@ mod renderer;
 mod component;
 #[allow(dead_code)]
 mod state;
+mod net;
+mod chat;
+mod world;
 
-use std::io::prelude::*;
-use std::net::{TcpStream, UdpSocket, SocketAddr};
+use std::net::{UdpSocket, SocketAddr};
 use glium::backend::glutin_backend::GlutinFacade;
-use common::net::{Packet, RegPacket};
+use common::net::{AnyPacket, GameJoinPacket, DisconnectPacket, SnapshotAckPacket,
+                  Channel, INPUT_LEFT, INPUT_RIGHT, INPUT_JUMP};
 
 fn setup_display() -> GlutinFacade {
   use glium::DisplayBuild;
@@ -35,10 +38,6 @@ fn main() {
     w.register::<CompAABB>();
     w.register::<CompBody>();
     w.register::<CompColor>();
-    w.create_now().with(CompAABB([0.0, 0.0, 32.0, 32.0]))
-      .with(CompColor([0.0, 1.0, 0.0, 1.0]))
-      .with(CompBody{vel: [0.0, 0.0], acc: [0.5, 0.3], mass: 5.0, flags: BODY_GRAVITY})
-      .build();
     specs::Planner::new(w)
   };
 
@@ -50,38 +49,162 @@ fn main() {
   let server_udp_addr : SocketAddr = "127.0.0.1:12345".parse().unwrap();
   let server_tcp_addr : SocketAddr = "127.0.0.1:12346".parse().unwrap();
 
-  {
-    // Send some UDP data
-    let socket = UdpSocket::bind(this_addr).unwrap();
-    socket.connect(server_udp_addr).unwrap(); // Connect to the server on localhost
-    socket.send(&[65, 0]).unwrap();
+  // Connect to the TCP listener, and check the server speaks our protocol
+  let mut server = net::ServerStream::connect(server_tcp_addr).unwrap();
+  if let Err(e) = net::hello(&mut server) {
+    println!("Couldn't connect to the server: {}", e);
+    return;
   }
 
-  {
-    // Connect to the TCP listener
-    let mut stream = TcpStream::connect(server_tcp_addr).unwrap();
-    // Register us with the name 'John'
-    let reg_packet = RegPacket::new("John");
-    stream.write(&reg_packet.serialise()).unwrap();
+  // Register us with the name 'John'. The server must accept this before we
+  // join a game.
+  let reg = net::register(&mut server, "John").unwrap();
+  println!("Registered with client ID {}", reg.client_id);
+
+  // Bind our UDP socket to the session, so the server accepts its datagrams
+  let udp_socket = UdpSocket::bind(this_addr).unwrap();
+  udp_socket.connect(server_udp_addr).unwrap(); // Connect to the server on localhost
+  net::bind_udp(&udp_socket, reg.session_token).unwrap();
+  let mut server_udp = net::ServerSocket::new(udp_socket).unwrap();
+
+  // Show the games running on the server, then join whichever has space
+  for room in net::list_games(&mut server).unwrap() {
+    println!("Room {}: {}/{} players, {:?}", room.id, room.players, room.max_players, room.state);
   }
+  let room_id = net::join_game(&mut server, GameJoinPacket::quick_match()).unwrap();
+  println!("Joined room {}", room_id);
+
+  // Ready up, and wait for everyone else in the room to do the same
+  net::set_ready(&mut server, true).unwrap();
+  let start = net::wait_for_match(&mut server, &mut server_udp).unwrap();
+  println!("Match starting on map \"{}\" at {} ticks per second",
+           start.settings.map, start.settings.tickrate);
+
+  // Predict our player at the server's tickrate, and draw everything else
+  // where the server last said it was
+  let mut snapshots = net::SnapshotReceiver::new();
+  let mut prediction = net::Prediction::new(reg.client_id, start.settings.tickrate);
+  let mut world_sync = world::WorldSync::new();
+  let mut buttons = 0u8;
+  global_state.prev_time = time::precise_time_ns();
+
+  // Chat is drawn over the top of the game
+  let mut chat = chat::ChatOverlay::new();
+  let overlay_controller = renderer.get_renderer_controller();
 
   loop {
     // Check input
     for ev in display.poll_events() {
       use glium::glutin::Event;
       match ev {
-        Event::Closed => return,
-          _ => ()
+        Event::Closed => {
+          // Let the server tell everyone else we've gone
+          let _ = server.send(&DisconnectPacket { reason: "quit".to_owned() });
+          return;
+        }
+        Event::KeyboardInput(key_state, _, Some(key)) => {
+          use glium::glutin::{ElementState, VirtualKeyCode};
+          let button = match key {
+            VirtualKeyCode::A | VirtualKeyCode::Left => INPUT_LEFT,
+            VirtualKeyCode::D | VirtualKeyCode::Right => INPUT_RIGHT,
+            VirtualKeyCode::W | VirtualKeyCode::Up | VirtualKeyCode::Space => INPUT_JUMP,
+            _ => 0,
+          };
+          match key_state {
+            // Keys typed into chat don't move the player
+            ElementState::Pressed if !chat.is_typing() => buttons |= button,
+            ElementState::Pressed => (),
+            ElementState::Released => buttons &= !button,
+          }
+          prediction.set_buttons(buttons);
+        }
+        Event::Focused(false) => {
+          // We won't hear about keys released while unfocused
+          buttons = 0;
+          prediction.set_buttons(buttons);
+        }
+        Event::ReceivedCharacter(c) => {
+          if let Some(message) = chat.handle_char(c) {
+            if let Err(e) = server.send(&message) {
+              println!("Failed to send chat message: {}", e);
+            }
+          }
+        }
+        _ => ()
+      }
+    }
+
+    // Handle packets from the server
+    loop {
+      match server.try_recv() {
+        Ok(Some(AnyPacket::ChatBroadcast(message))) => {
+          chat.push_message(&message, time::precise_time_ns());
+        }
+        Ok(Some(AnyPacket::ChatReject(reject))) => {
+          chat.push_notice(&format!("Message not sent: {}", reject.reason), time::precise_time_ns());
+        }
+        Ok(Some(AnyPacket::PlayerLeft(left))) => {
+          chat.push_notice(&format!("{} left ({})", left.name, left.reason), time::precise_time_ns());
+        }
+        Ok(Some(AnyPacket::Disconnect(disconnect))) => {
+          println!("Disconnected by the server: {}", disconnect.reason);
+          return;
+        }
+        Ok(Some(_)) => (),
+        Ok(None) => break,
+        Err(e) => {
+          println!("Lost connection to the server: {}", e);
+          return;
+        }
       }
     }
 
+    // Answer heartbeats, so the server knows we're still here, and reconcile
+    // our prediction with the server's snapshots
+    match server_udp.try_recv() {
+      Ok(packets) => for packet in packets {
+        match packet {
+          AnyPacket::Ping(ping) => {
+            if let Err(e) = server_udp.answer_ping(&ping) {
+              println!("Failed to answer ping: {}", e);
+            }
+          }
+          AnyPacket::DeltaSnapshot(delta) => match snapshots.receive(&delta) {
+            Ok(Some(snapshot)) => {
+              let _ = server_udp.send(Channel::Unreliable, &SnapshotAckPacket { tick: snapshot.tick });
+              prediction.receive_snapshot(snapshot, server_udp.rtt());
+              world_sync.apply(planner.mut_world(), snapshot);
+            }
+            Ok(None) => (),
+            Err(e) => println!("Bad snapshot from the server: {}", e),
+          },
+          _ => (),
+        }
+      },
+      Err(e) => println!("Failed to receive from the server: {}", e),
+    }
+
     // Calculate frame delta, store in global state object
     global_state.delta = time::precise_time_ns() - global_state.prev_time;
     global_state.prev_time = time::precise_time_ns();
 
+    // Move our player, sending the server any change in input
+    for input in prediction.advance(global_state.get_delta_in_s()) {
+      if let Err(e) = server_udp.send(Channel::Unreliable, &input) {
+        println!("Failed to send input: {}", e);
+      }
+    }
+    if let Some(aabb) = prediction.player_aabb() {
+      world_sync.set_aabb(planner.mut_world(), reg.client_id, aabb);
+    }
+    if let Err(e) = server_udp.flush() {
+      println!("Failed to send to the server: {}", e);
+    }
+
     // Dispatch ECS with the global state object
     planner.dispatch(global_state.clone());
     planner.wait();
+    chat.draw(&overlay_controller, time::precise_time_ns());
 
     // Receive any vertex data sent by the ECS
     renderer.recv_data();
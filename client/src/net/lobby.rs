@@ -0,0 +1,56 @@
+//! Waiting in a room's lobby for the match to start.
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+use common::net::{AnyPacket, ReadyPacket, MatchStartPacket};
+use net::{ServerStream, ServerSocket};
+
+/// How long to sleep between checking the sockets while waiting in the lobby.
+const LOBBY_POLL_INTERVAL_MS : u64 = 10;
+
+/// Mark ourselves as ready, or not ready, for the match to start.
+pub fn set_ready(server: &mut ServerStream, ready: bool) -> io::Result<()> {
+  server.send(&ReadyPacket { ready })
+}
+
+/// Block until the match starts, printing the lobby as it changes. Pings from
+/// the server are answered while we wait, or it would time us out if the
+/// other players take a while to ready up.
+pub fn wait_for_match(server: &mut ServerStream, server_udp: &mut ServerSocket)
+    -> io::Result<MatchStartPacket> {
+  loop {
+    match server_udp.try_recv() {
+      Ok(packets) => for packet in packets {
+        if let AnyPacket::Ping(ping) = packet { server_udp.answer_ping(&ping)?; }
+      },
+      Err(e) => println!("Failed to receive from the server: {}", e),
+    }
+    if let Err(e) = server_udp.flush() {
+      println!("Failed to send to the server: {}", e);
+    }
+
+    let packet = match server.try_recv()? {
+      Some(packet) => packet,
+      None => {
+        thread::sleep(Duration::from_millis(LOBBY_POLL_INTERVAL_MS));
+        continue;
+      }
+    };
+    match packet {
+      AnyPacket::MatchStart(start) => return Ok(start),
+      AnyPacket::LobbyState(lobby) => {
+        println!("Room {} on map \"{}\", {}/{} players:", lobby.room_id, lobby.settings.map,
+                 lobby.players.len(), lobby.settings.max_players);
+        for p in &lobby.players {
+          println!("  {}{}{}", p.name,
+                   if p.client_id == lobby.host { " (host)" } else { "" },
+                   if p.ready { " - ready" } else { "" });
+        }
+      }
+      AnyPacket::LobbyReject(reject) => println!("Lobby request rejected: {}", reject.reason),
+      other => println!("Ignoring unexpected {} packet in the lobby",
+                        String::from_utf8_lossy(&other.tag())),
+    }
+  }
+}
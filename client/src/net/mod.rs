@@ -0,0 +1,16 @@
+//! Client side networking - the connection handshake, and decoding state sent
+//! by the server.
+
+mod snapshot;
+mod prediction;
+mod stream;
+mod udp;
+mod session;
+mod lobby;
+
+pub use self::snapshot::SnapshotReceiver;
+pub use self::prediction::Prediction;
+pub use self::stream::ServerStream;
+pub use self::udp::ServerSocket;
+pub use self::session::{hello, register, bind_udp, list_games, join_game};
+pub use self::lobby::{set_ready, wait_for_match};
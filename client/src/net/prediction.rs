@@ -0,0 +1,481 @@
+//! Client side prediction of the local player, and reconciliation with the
+//! server (see netcode_notes.txt). Inputs are applied to the local player
+//! straight away through the same physics step as the server, and queued
+//! until the server acknowledges them. When a snapshot arrives, the player is
+//! rewound to the server's state and the unacknowledged inputs are replayed on
+//! top of it.
+//!
+//! The client runs ahead of the server, far enough that its inputs arrive just
+//! before the server simulates the ticks they were made on.
+//!
+//! Other entities aren't predicted - they're obstacles at the positions in
+//! the latest snapshot.
+//!
+//! Inputs are only sent when they change, so a lost input would leave the
+//! server's player standing still while a key is held, or moving after it's
+//! released. When a snapshot is past the tick of our latest input but still
+//! doesn't acknowledge it, and the server's velocity for the player disagrees
+//! with the held keys, the held input is resent as if it had just been
+//! pressed.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use common::net::{InputPacket, SnapshotPacket};
+use common::physics::{self, Body, Object};
+
+/// Corrections further than this are snapped to, rather than smoothed.
+pub const SNAP_DISTANCE : f32 = 64.0;
+/// The fraction of a correction still left to smooth out after each tick.
+pub const SMOOTHING : f32 = 0.85;
+/// The most time simulated at once, in seconds. If the client stalls for
+/// longer than this, the extra time is dropped.
+pub const MAX_CATCH_UP : f32 = 0.25;
+/// How much earlier than needed inputs should reach the server, in seconds,
+/// to allow for jitter in the round trip time.
+pub const INPUT_MARGIN : f32 = 0.05;
+/// How many ticks the prediction can fall behind where it should be before
+/// it's jumped forward, rather than jumping on every change in round trip
+/// time.
+pub const RESYNC_TICKS : u32 = 2;
+/// Corrections smaller than this are put down to quantization, rather than
+/// the server disagreeing with us.
+pub const CORRECTION_THRESHOLD : f32 = 0.5;
+/// The most the server's velocity can differ from the held keys before
+/// they're resent, in units per second. This allows for quantization.
+pub const VELOCITY_TOLERANCE : f32 = 1.0;
+
+/// Predicts the local player's movement.
+pub struct Prediction {
+  /// The ID of the local player's entity, which is its client ID.
+  player_id: u32,
+  /// The length of a game tick, in seconds.
+  tick_len: f32,
+  /// Time passed which hasn't been simulated yet, in seconds.
+  leftover: f32,
+  /// The tick predicted up to, or `None` before the first snapshot.
+  tick: Option<u32>,
+  /// The buttons currently held.
+  buttons: u8,
+  /// Whether to resend the held buttons on the next tick, even if they
+  /// haven't changed.
+  resend: bool,
+  /// The latest input the server has acknowledged. It's in effect until the
+  /// first input in `inputs`.
+  acked_input: Option<InputPacket>,
+  /// Inputs the server hasn't acknowledged yet, oldest first.
+  inputs: VecDeque<InputPacket>,
+  /// The predicted local player.
+  player: Option<Object>,
+  /// Everything else in the world, from the latest snapshot.
+  obstacles: Vec<Object>,
+  /// The offset from the predicted to the drawn position of the player. This
+  /// decays every tick, so corrections are smoothed rather than snapped to.
+  error: [f32; 2],
+}
+
+impl Prediction {
+  /// Create a prediction for the local player.
+  /// # Params
+  /// * `player_id` - The client ID given to us when we registered.
+  /// * `tickrate` - The game tickrate of the match.
+  pub fn new(player_id: u32, tickrate: u32) -> Prediction {
+    Prediction {
+      player_id,
+      tick_len: physics::tick_len(tickrate),
+      leftover: 0.0,
+      tick: None,
+      buttons: 0,
+      resend: false,
+      acked_input: None,
+      inputs: VecDeque::new(),
+      player: None,
+      obstacles: Vec::new(),
+      error: [0.0, 0.0],
+    }
+  }
+
+  /// Set the buttons held. These are sent from the next tick.
+  pub fn set_buttons(&mut self, buttons: u8) {
+    self.buttons = buttons;
+  }
+
+  /// Where to draw the local player, X, Y, W, H.
+  pub fn player_aabb(&self) -> Option<[f32; 4]> {
+    self.player.map(|p| {
+      [p.aabb[0] + self.error[0], p.aabb[1] + self.error[1], p.aabb[2], p.aabb[3]]
+    })
+  }
+
+  /// Simulate the time passed since the last call, a tick at a time.
+  /// # Params
+  /// * `dt` - The time passed, in seconds.
+  /// # Returns
+  /// The inputs made, to send to the server.
+  pub fn advance(&mut self, dt: f32) -> Vec<InputPacket> {
+    let mut sent = Vec::new();
+    let mut tick = match self.tick {
+      Some(tick) => tick,
+      None => return sent,
+    };
+    self.leftover = (self.leftover + dt).min(MAX_CATCH_UP);
+    while self.leftover >= self.tick_len {
+      self.leftover -= self.tick_len;
+      tick += 1;
+      // The input state is sent whenever it changes, or the server seems to
+      // have missed it
+      if self.resend || self.input_at(tick).map_or(0, |i| i.buttons) != self.buttons {
+        self.resend = false;
+        let input = InputPacket::new(tick, self.buttons);
+        self.inputs.push_back(input);
+        sent.push(input);
+      }
+      self.simulate(tick);
+      self.error = [self.error[0] * SMOOTHING, self.error[1] * SMOOTHING];
+    }
+    self.tick = Some(tick);
+    sent
+  }
+
+  /// Reconcile the prediction with a snapshot from the server. Acknowledged
+  /// inputs are dropped, then the player is rewound to its state in the
+  /// snapshot and every tick since is replayed with the remaining inputs. If
+  /// the server should have simulated our latest input by now but hasn't
+  /// acknowledged it, and has the player moving differently to the held keys,
+  /// the held input is resent on the next tick.
+  /// # Params
+  /// * `snapshot` - The snapshot received.
+  /// * `rtt` - The round trip time to the server, if it's known yet.
+  pub fn receive_snapshot(&mut self, snapshot: &SnapshotPacket, rtt: Option<Duration>) {
+    let state = match snapshot.entities.iter().find(|e| e.id == self.player_id) {
+      Some(state) => *state,
+      None => return,
+    };
+    self.obstacles = snapshot.entities.iter()
+      .filter(|e| e.id != self.player_id)
+      .map(|e| Object { aabb: e.aabb, body: None })
+      .collect();
+
+    // Pop inputs from the front of the queue until the one at the front
+    // occurred after the server's update
+    if let Some(ack) = snapshot.acks.iter().find(|a| a.client_id == self.player_id) {
+      while self.inputs.front().is_some_and(|i| i.tick <= ack.tick) {
+        self.acked_input = self.inputs.pop_front();
+      }
+    }
+
+    // Until the server reaches the tick of an input it will disagree with us
+    // anyway, so only an input it's gone past without acknowledging is lost
+    let lost = self.inputs.back().is_some_and(|i| i.tick <= snapshot.tick);
+
+    let predicted = self.player;
+    let mut body = Body::player(state.vel);
+    body.set_grounded(state.grounded);
+    self.player = Some(Object { aabb: state.aabb, body: Some(body) });
+    // The prediction is never moved back, as the server ignores inputs older
+    // than ones it already has
+    let target = self.target_tick(snapshot.tick, rtt);
+    let current = match self.tick {
+      Some(tick) if tick + RESYNC_TICKS >= target => tick,
+      _ => target,
+    };
+    self.tick = Some(current);
+    for tick in snapshot.tick + 1..=current { self.simulate(tick); }
+
+    // Draw the player where it was, moving smoothly to the corrected position
+    if let (Some(before), Some(after)) = (predicted, self.player) {
+      let error = [before.aabb[0] + self.error[0] - after.aabb[0],
+                   before.aabb[1] + self.error[1] - after.aabb[1]];
+      let distance = (error[0] * error[0] + error[1] * error[1]).sqrt();
+      self.error = if distance > SNAP_DISTANCE { [0.0, 0.0] } else { error };
+      if lost && distance > CORRECTION_THRESHOLD && !self.matches_buttons(state.vel) {
+        self.resend = true;
+      }
+    }
+  }
+
+  /// The tick to predict up to when a snapshot arrives. By then the server is
+  /// half a round trip past the snapshot, and our inputs take another half to
+  /// reach it, so we run a whole round trip plus `INPUT_MARGIN` ahead.
+  fn target_tick(&self, snapshot_tick: u32, rtt: Option<Duration>) -> u32 {
+    let ahead = rtt.map_or(0.0, |rtt| rtt.as_secs_f32()) + INPUT_MARGIN;
+    snapshot_tick + (ahead / self.tick_len).ceil() as u32
+  }
+
+  /// Whether a velocity from the server is what the held keys would give.
+  fn matches_buttons(&self, vel: [f32; 2]) -> bool {
+    let mut body = Body::player(vel);
+    physics::apply_input(&mut body, &InputPacket::new(0, self.buttons));
+    (body.vel[0] - vel[0]).abs() <= VELOCITY_TOLERANCE
+  }
+
+  /// The input in effect on a tick.
+  fn input_at(&self, tick: u32) -> Option<&InputPacket> {
+    self.inputs.iter().rev().find(|i| i.tick <= tick).or(self.acked_input.as_ref())
+  }
+
+  /// Simulate a tick, from the state after the tick before.
+  fn simulate(&mut self, tick: u32) {
+    let mut player = match self.player {
+      Some(player) => player,
+      None => return,
+    };
+    if let (Some(input), Some(body)) = (self.input_at(tick).cloned(), player.body.as_mut()) {
+      physics::apply_input(body, &input);
+    }
+    // The player is stepped first, as the obstacles never move
+    let mut world = Vec::with_capacity(self.obstacles.len() + 1);
+    world.push(player);
+    world.extend_from_slice(&self.obstacles);
+    physics::step(&mut world, self.tick_len);
+    self.player = Some(world[0]);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::{BTreeMap, VecDeque};
+  use std::time::Duration;
+  use common::net::{InputAck, EntityState, InputPacket, SnapshotPacket, Wire, WireReader,
+                    INPUT_RIGHT, POSITION_QUANTIZER};
+  use common::physics::{self, Body, Object, MOVE_SPEED};
+  use super::{Prediction, CORRECTION_THRESHOLD, RESYNC_TICKS};
+
+  const TICKRATE : u32 = 60;
+  const PLAYER_ID : u32 = 1;
+  /// Snapshots are sent every few ticks.
+  const SNAPSHOT_INTERVAL : u32 = 3;
+  /// How many ticks a packet takes to arrive each way, so the round trip is
+  /// 200ms.
+  const LATENCY : u32 = 6;
+
+  fn tick_len() -> f32 {
+    physics::tick_len(TICKRATE)
+  }
+
+  fn rtt() -> Option<Duration> {
+    Some(Duration::from_secs(1) * 2 * LATENCY / TICKRATE)
+  }
+
+  /// Round trip an entity's state through its wire encoding, so it's
+  /// quantized as it would be on the wire.
+  fn quantize(state: &EntityState) -> EntityState {
+    let mut buf = Vec::new();
+    state.encode(&mut buf);
+    EntityState::decode(&mut WireReader::new(&buf)).unwrap()
+  }
+
+  /// A server with one player standing on the ground. An input takes effect
+  /// from its own tick if it arrives in time, or from when it arrives if it's
+  /// late. The client runs far enough ahead that inputs should never be late.
+  struct Server {
+    tick: u32,
+    ground: Object,
+    player: Object,
+    inputs: BTreeMap<u32, InputPacket>,
+    /// The tick of the latest input received.
+    last_input: Option<u32>,
+    /// How many inputs arrived after their tick had been simulated.
+    late_inputs: usize,
+  }
+
+  impl Server {
+    fn new() -> Server {
+      Server {
+        tick: 0,
+        ground: Object { aabb: [0.0, 500.0, 800.0, 100.0], body: None },
+        player: Object { aabb: [100.0, 468.0, 32.0, 32.0], body: Some(Body::player([0.0, 0.0])) },
+        inputs: BTreeMap::new(),
+        last_input: None,
+        late_inputs: 0,
+      }
+    }
+
+    fn receive_input(&mut self, input: InputPacket) {
+      if input.tick <= self.tick { self.late_inputs += 1; }
+      self.inputs.insert(input.tick.max(self.tick + 1), input);
+      self.last_input = Some(self.last_input.map_or(input.tick, |tick| tick.max(input.tick)));
+    }
+
+    fn step(&mut self) {
+      self.tick += 1;
+      if let Some((_, input)) = self.inputs.range(..=self.tick).next_back() {
+        physics::apply_input(self.player.body.as_mut().unwrap(), input);
+      }
+      let mut world = [self.ground, self.player];
+      physics::step(&mut world, tick_len());
+      self.player = world[1];
+    }
+
+    fn snapshot(&self) -> SnapshotPacket {
+      let state = |id, o: &Object| quantize(&EntityState {
+        id,
+        aabb: o.aabb,
+        vel: o.body.map_or([0.0, 0.0], |b| b.vel),
+        color: [1.0, 1.0, 1.0, 1.0],
+        grounded: o.body.is_some_and(|b| b.is_grounded()),
+      });
+      SnapshotPacket {
+        tick: self.tick,
+        acks: self.last_input.map(|tick| InputAck { client_id: PLAYER_ID, tick })
+          .into_iter().collect(),
+        entities: vec![state(0, &self.ground), state(PLAYER_ID, &self.player)],
+      }
+    }
+  }
+
+  /// Runs a client against the server over a link with latency, which can
+  /// drop inputs.
+  struct Link {
+    server: Server,
+    client: Prediction,
+    /// Inputs and snapshots on their way, with the server tick they arrive
+    /// on.
+    inputs_in_flight: VecDeque<(u32, InputPacket)>,
+    snapshots_in_flight: VecDeque<(u32, SnapshotPacket)>,
+    /// How many of the next inputs to drop.
+    drop_inputs: usize,
+    sent: usize,
+  }
+
+  impl Link {
+    fn new() -> Link {
+      let mut link = Link {
+        server: Server::new(),
+        client: Prediction::new(PLAYER_ID, TICKRATE),
+        inputs_in_flight: VecDeque::new(),
+        snapshots_in_flight: VecDeque::new(),
+        drop_inputs: 0,
+        sent: 0,
+      };
+      // Let the client sync to the server before the test starts
+      link.run(30);
+      link.sent = 0;
+      link
+    }
+
+    fn run(&mut self, ticks: u32) {
+      for _ in 0..ticks {
+        for input in self.client.advance(tick_len()) {
+          self.sent += 1;
+          if self.drop_inputs > 0 {
+            self.drop_inputs -= 1;
+          } else {
+            self.inputs_in_flight.push_back((self.server.tick + LATENCY, input));
+          }
+        }
+        while self.inputs_in_flight.front().is_some_and(|&(at, _)| at <= self.server.tick) {
+          let (_, input) = self.inputs_in_flight.pop_front().unwrap();
+          self.server.receive_input(input);
+        }
+        self.server.step();
+        if self.server.tick.is_multiple_of(SNAPSHOT_INTERVAL) {
+          self.snapshots_in_flight.push_back((self.server.tick + LATENCY, self.server.snapshot()));
+        }
+        while self.snapshots_in_flight.front().is_some_and(|&(at, _)| at <= self.server.tick) {
+          let (_, snapshot) = self.snapshots_in_flight.pop_front().unwrap();
+          self.client.receive_snapshot(&snapshot, rtt());
+        }
+      }
+    }
+
+    fn server_x(&self) -> f32 {
+      self.server.player.aabb[0]
+    }
+
+    /// How far the drawn player is from where it's predicted to be.
+    fn error(&self) -> f32 {
+      self.client.error[0].abs().max(self.client.error[1].abs())
+    }
+  }
+
+  #[test]
+  fn prediction_runs_ahead_by_the_round_trip() {
+    let mut client = Prediction::new(PLAYER_ID, TICKRATE);
+    client.receive_snapshot(&Server::new().snapshot(), Some(Duration::from_millis(100)));
+    // A round trip is 6 ticks, then a margin on top
+    let tick = client.tick.unwrap();
+    assert!(tick > 6 && tick <= 6 + RESYNC_TICKS + 2, "predicting tick {}", tick);
+
+    // Falling behind jumps forward, but a shorter round trip never moves back
+    let mut snapshot = Server::new().snapshot();
+    snapshot.tick = 20;
+    client.receive_snapshot(&snapshot, Some(Duration::from_millis(100)));
+    assert!(client.tick.unwrap() > 26);
+    let ahead = client.tick;
+    snapshot.tick = 21;
+    client.receive_snapshot(&snapshot, Some(Duration::from_millis(20)));
+    assert_eq!(client.tick, ahead);
+  }
+
+  #[test]
+  fn unacked_inputs_are_replayed() {
+    let server = Server::new();
+    let mut client = Prediction::new(PLAYER_ID, TICKRATE);
+    client.receive_snapshot(&server.snapshot(), None);
+    client.set_buttons(INPUT_RIGHT);
+    client.advance(10.0 * tick_len());
+    let pressed = client.inputs[0].tick;
+
+    // The server hasn't had the input yet, so the player hasn't moved. The
+    // input is kept, and in effect for every tick replayed.
+    let mut snapshot = server.snapshot();
+    snapshot.tick = pressed + 2;
+    client.receive_snapshot(&snapshot, None);
+    assert_eq!(client.inputs.len(), 1);
+    let ticks = client.tick.unwrap() - snapshot.tick;
+    let moved = client.player.unwrap().aabb[0] - snapshot.entities[1].aabb[0];
+    let expected = ticks as f32 * MOVE_SPEED * tick_len();
+    assert!((moved - expected).abs() < 0.01, "moved {}, expected {}", moved, expected);
+  }
+
+  #[test]
+  fn inputs_arrive_before_their_tick() {
+    let mut link = Link::new();
+    link.client.set_buttons(INPUT_RIGHT);
+    link.run(30);
+    link.client.set_buttons(0);
+    link.run(30);
+    assert_eq!(link.server.late_inputs, 0);
+    // The server agrees with everything predicted, give or take quantization
+    assert!(link.error() < CORRECTION_THRESHOLD, "corrected by {}", link.error());
+    let predicted = link.client.player.unwrap().aabb;
+    assert!((predicted[0] - link.server_x()).abs() <= POSITION_QUANTIZER.max_error());
+  }
+
+  #[test]
+  fn inputs_are_only_sent_when_they_change() {
+    let mut link = Link::new();
+    link.client.set_buttons(INPUT_RIGHT);
+    link.run(60);
+    // The server's player starts moving a round trip after the client's, which
+    // mustn't be mistaken for a lost input
+    assert_eq!(link.sent, 1);
+    assert!(link.server_x() > 250.0, "server player at {}", link.server_x());
+  }
+
+  #[test]
+  fn held_key_is_resent_when_the_press_is_lost() {
+    let mut link = Link::new();
+    link.client.set_buttons(INPUT_RIGHT);
+    link.drop_inputs = 1;
+    link.run(60);
+    assert!(link.sent > 1);
+    assert!(link.server_x() > 150.0, "server player at {}", link.server_x());
+    // The prediction catches up with the server once it has the input
+    let predicted = link.client.player.unwrap().body.unwrap().vel;
+    assert_eq!(predicted[0], link.server.player.body.unwrap().vel[0]);
+  }
+
+  #[test]
+  fn release_is_resent_when_it_is_lost() {
+    let mut link = Link::new();
+    link.client.set_buttons(INPUT_RIGHT);
+    link.run(30);
+    link.client.set_buttons(0);
+    link.drop_inputs = 1;
+    link.run(60);
+    assert!(link.sent > 2);
+    assert_eq!(link.server.player.body.unwrap().vel[0], 0.0);
+    assert!(link.error() < CORRECTION_THRESHOLD, "corrected by {}", link.error());
+  }
+}
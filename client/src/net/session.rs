@@ -0,0 +1,112 @@
+//! The handshake run when connecting to the server - agreeing on a protocol
+//! version, registering over TCP, then binding our UDP socket to the session
+//! the server hands back. After this, a game room can be found and joined.
+
+use std::io;
+use std::net::UdpSocket;
+use std::time::Duration;
+use common::net::{AnyPacket, UdpHelloPacket, RegPacket, RegAckPacket, HelloPacket, HelloAckPacket,
+                  GameJoinPacket, GameListRequestPacket, RoomInfo, FrameEncoder};
+use net::ServerStream;
+
+/// How long to wait for the server to echo a UDP hello before resending it.
+const HELLO_RESEND_MS : u64 = 200;
+/// How many times to send a UDP hello before giving up.
+const HELLO_ATTEMPTS : u32 = 25;
+
+/// Send our protocol version to the server, blocking until it replies. This
+/// must be the first thing sent to the server.
+/// # Returns
+/// The server's acknowledgement, with the features we've agreed to use. If
+/// the server can't talk to us, an error of kind `Unsupported` with the
+/// server's reason.
+pub fn hello(server: &mut ServerStream) -> io::Result<HelloAckPacket> {
+  server.send(&HelloPacket::new())?;
+  loop {
+    match server.recv()? {
+      AnyPacket::HelloAck(ack) => return Ok(ack),
+      AnyPacket::HelloReject(reject) => return Err(io::Error::new(
+        io::ErrorKind::Unsupported, format!("server rejected our protocol: {}", reject.reason))),
+      other => println!("Ignoring unexpected {} packet during the protocol handshake",
+                        String::from_utf8_lossy(&other.tag())),
+    }
+  }
+}
+
+/// Register with the server under a name, blocking until it replies. Nothing
+/// else should be sent to the server until this returns.
+/// # Returns
+/// The server's acknowledgement, with our client ID and the session token to
+/// bind our UDP socket with. If the server rejects the name, an error of kind
+/// `InvalidInput` with the server's reason.
+pub fn register(server: &mut ServerStream, name: &str) -> io::Result<RegAckPacket> {
+  server.send(&RegPacket::new(name))?;
+  loop {
+    match server.recv()? {
+      AnyPacket::RegAck(ack) => return Ok(ack),
+      AnyPacket::RegReject(reject) => return Err(io::Error::new(
+        io::ErrorKind::InvalidInput, format!("registration rejected: {}", reject.reason))),
+      other => println!("Ignoring unexpected {} packet while registering",
+                        String::from_utf8_lossy(&other.tag())),
+    }
+  }
+}
+
+/// Send our session token to the server over UDP, so it knows which address
+/// our datagrams come from. The hello is resent until the server echoes it.
+/// # Params
+/// * `socket` - A UDP socket connected to the server's UDP address.
+/// * `session_token` - The token returned by `register`.
+pub fn bind_udp(socket: &UdpSocket, session_token: u64) -> io::Result<()> {
+  let hello = FrameEncoder::datagram().encode(&UdpHelloPacket { session_token })
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  let mut buf = [0; 64];
+  socket.set_read_timeout(Some(Duration::from_millis(HELLO_RESEND_MS)))?;
+  for _ in 0..HELLO_ATTEMPTS {
+    socket.send(&hello)?;
+    match socket.recv(&mut buf) {
+      Ok(len) if buf[..len] == hello[..] => {
+        socket.set_read_timeout(None)?;
+        return Ok(());
+      }
+      // Anything else is a stale datagram, or the hello or echo was lost
+      Ok(_) => (),
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+      Err(e) => return Err(e),
+    }
+  }
+  Err(io::Error::new(io::ErrorKind::TimedOut, "server didn't acknowledge the UDP hello"))
+}
+
+/// Ask the server for the list of rooms, blocking until it replies.
+pub fn list_games(server: &mut ServerStream) -> io::Result<Vec<RoomInfo>> {
+  server.send(&GameListRequestPacket)?;
+  loop {
+    match server.recv()? {
+      AnyPacket::GameList(list) => return Ok(list.rooms),
+      other => println!("Ignoring unexpected {} packet while listing games",
+                        String::from_utf8_lossy(&other.tag())),
+    }
+  }
+}
+
+/// Join a game room, blocking until the server replies. We must have
+/// registered first.
+/// # Params
+/// * `server` - The stream to the server.
+/// * `join` - The room to join, or a quick match.
+/// # Returns
+/// The ID of the room joined. If we couldn't join, an error of kind `Other`
+/// with the server's reason.
+pub fn join_game(server: &mut ServerStream, join: GameJoinPacket) -> io::Result<u32> {
+  server.send(&join)?;
+  loop {
+    match server.recv()? {
+      AnyPacket::GameJoinAck(ack) => return Ok(ack.room_id),
+      AnyPacket::GameJoinReject(reject) => return Err(io::Error::other(
+        format!("couldn't join the game: {}", reject.reason))),
+      other => println!("Ignoring unexpected {} packet while joining a game",
+                        String::from_utf8_lossy(&other.tag())),
+    }
+  }
+}
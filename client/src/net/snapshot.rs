@@ -0,0 +1,41 @@
+use std::collections::VecDeque;
+use common::net::{SnapshotPacket, DeltaSnapshotPacket, DeserialiseError, SNAPSHOT_HISTORY_LEN};
+
+/// Decodes delta snapshots from the server, keeping a history of received
+/// snapshots to use as baselines.
+pub struct SnapshotReceiver {
+  /// Received snapshots, oldest first.
+  history: VecDeque<SnapshotPacket>,
+}
+
+impl SnapshotReceiver {
+  pub fn new() -> SnapshotReceiver {
+    SnapshotReceiver { history: VecDeque::with_capacity(SNAPSHOT_HISTORY_LEN) }
+  }
+
+  /// Decode a delta snapshot against its baseline.
+  /// # Returns
+  /// The full snapshot, or `None` if it's older than the latest snapshot
+  /// received and so was discarded. Once a snapshot is returned, its tick
+  /// should be acknowledged to the server with a `SnapshotAckPacket`.
+  pub fn receive(&mut self, delta: &DeltaSnapshotPacket)
+      -> Result<Option<&SnapshotPacket>, DeserialiseError> {
+    if self.latest().is_some_and(|s| s.tick >= delta.tick) { return Ok(None); }
+
+    let snapshot = {
+      let baseline = delta.baseline
+        .and_then(|tick| self.history.iter().find(|s| s.tick == tick));
+      delta.apply(baseline)?
+    };
+    if self.history.len() == SNAPSHOT_HISTORY_LEN {
+      self.history.pop_front();
+    }
+    self.history.push_back(snapshot);
+    Ok(self.history.back())
+  }
+
+  /// The most recent snapshot received.
+  pub fn latest(&self) -> Option<&SnapshotPacket> {
+    self.history.back()
+  }
+}
@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use common::net::{AnyPacket, Packet, FrameEncoder, FrameDecoder};
+
+/// A blocking TCP stream to the server, sending and receiving whole packets.
+pub struct ServerStream {
+  stream: TcpStream,
+  /// Data received but not yet parsed into packets.
+  decoder: FrameDecoder,
+}
+
+impl ServerStream {
+  pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<ServerStream> {
+    Ok(ServerStream { stream: TcpStream::connect(addr)?, decoder: FrameDecoder::default() })
+  }
+
+  pub fn send<P: Packet>(&mut self, packet: &P) -> io::Result<()> {
+    let frame = FrameEncoder::default().encode(packet)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    self.stream.write_all(&frame)
+  }
+
+  /// Block until a whole packet has arrived from the server.
+  /// # Returns
+  /// An `InvalidData` error if the server sent malformed data, or
+  /// `UnexpectedEof` if the server closed the connection.
+  pub fn recv(&mut self) -> io::Result<AnyPacket> {
+    let mut buf = [0; 4096];
+    loop {
+      if let Some(frame) = self.decoder.next_frame().map_err(invalid_data)? {
+        return AnyPacket::from_frame(&frame).map_err(invalid_data);
+      }
+      let len = self.stream.read(&mut buf)?;
+      if len == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"));
+      }
+      self.decoder.push(&buf[..len]);
+    }
+  }
+
+  /// Receive a packet if a whole one has arrived, without blocking.
+  pub fn try_recv(&mut self) -> io::Result<Option<AnyPacket>> {
+    self.stream.set_nonblocking(true)?;
+    let read = self.read_available();
+    self.stream.set_nonblocking(false)?;
+    read?;
+    match self.decoder.next_frame().map_err(invalid_data)? {
+      Some(frame) => AnyPacket::from_frame(&frame).map(Some).map_err(invalid_data),
+      None => Ok(None),
+    }
+  }
+
+  /// Read everything the server has sent so far into the decoder.
+  fn read_available(&mut self) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    loop {
+      match self.stream.read(&mut buf) {
+        Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                           "server closed the connection")),
+        Ok(len) => self.decoder.push(&buf[..len]),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+        Err(e) => return Err(e),
+      }
+    }
+  }
+}
+
+fn invalid_data<E: Into<Box<dyn Error + Send + Sync>>>(e: E) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,78 @@
+use std::io;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+use common::net::{AnyPacket, Packet, Channel, Connection, Frame, FrameEncoder, PingPacket, PongPacket,
+                  MAX_DATAGRAM_SIZE};
+
+/// A UDP socket bound to our session on the server, carrying packets over a
+/// `Connection`.
+pub struct ServerSocket {
+  socket: UdpSocket,
+  conn: Connection,
+}
+
+impl ServerSocket {
+  /// Wrap a socket which has already been bound with `bind_udp`.
+  pub fn new(socket: UdpSocket) -> io::Result<ServerSocket> {
+    socket.set_nonblocking(true)?;
+    Ok(ServerSocket { socket, conn: Connection::new() })
+  }
+
+  /// The round trip time to the server, once it's been measured.
+  pub fn rtt(&self) -> Option<Duration> {
+    self.conn.rtt()
+  }
+
+  /// Queue a packet to be sent on the next `flush`.
+  pub fn send<P: Packet>(&mut self, channel: Channel, packet: &P) -> io::Result<()> {
+    let frame = FrameEncoder::message().encode(packet)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    self.conn.send(channel, &frame, Instant::now())
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+  }
+
+  /// Answer a heartbeat from the server, so it knows we're still here. The
+  /// pong is sent on the next `flush`.
+  pub fn answer_ping(&mut self, ping: &PingPacket) -> io::Result<()> {
+    self.send(Channel::Unreliable, &PongPacket { seq: ping.seq })
+  }
+
+  /// Read every datagram waiting on the socket without blocking. Bad
+  /// datagrams are logged and dropped.
+  /// # Returns
+  /// The packets received, in the order they should be handled.
+  pub fn try_recv(&mut self) -> io::Result<Vec<AnyPacket>> {
+    let mut packets = Vec::new();
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    loop {
+      let len = match self.socket.recv(&mut buf) {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(packets),
+        Err(e) => return Err(e),
+      };
+      let messages = match self.conn.receive(&buf[..len], Instant::now()) {
+        Ok(messages) => messages,
+        Err(e) => {
+          println!("Ignoring bad datagram from the server: {}", e);
+          continue;
+        }
+      };
+      for (_, message) in messages {
+        match Frame::from_message(&message).and_then(|f| AnyPacket::from_frame(&f)) {
+          Ok(packet) => packets.push(packet),
+          Err(e) => println!("Ignoring bad message from the server: {}", e),
+        }
+      }
+    }
+  }
+
+  /// Send any queued datagrams, and resend reliable ones which weren't
+  /// acknowledged in time.
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.conn.update(Instant::now());
+    while let Some(datagram) = self.conn.poll_datagram() {
+      self.socket.send(&datagram)?;
+    }
+    Ok(())
+  }
+}
@@ -1,4 +1,5 @@
 use renderer::Vertex;
+use renderer::font::{self, GLYPH_W, GLYPH_H};
 use std::sync::mpsc;
 use std::ops::{Add, Sub, Mul};
 use nalgebra::Vector2;
@@ -71,4 +72,39 @@ impl RendererController {
     // Send the data
     self.sender.send(data).unwrap();
   }
+
+  /// Draws a line of text with the built in bitmap font.
+  /// #Params
+  /// * `pos` - The top left of the text
+  /// * `text` - The text to draw
+  /// * `scale` - The size of a font pixel
+  /// * `col` - The colour of the text
+  /// # Returns
+  /// The width of the text drawn
+  pub fn text(&self, pos: Vector2<f32>, text: &str, scale: f32, col: &[f32; 4]) -> f32 {
+    let mut data = Vec::new();
+    let mut x = pos.x;
+    for c in text.chars() {
+      for (gx, column) in font::glyph(c).iter().enumerate() {
+        for gy in 0..GLYPH_H {
+          if column & (1 << gy) == 0 { continue; }
+          let (px, py) = (x + gx as f32 * scale, pos.y + gy as f32 * scale);
+          // Tri 1
+          data.push( Vertex { pos: [px, py], col: col.clone() });
+          data.push( Vertex { pos: [px + scale, py], col: col.clone() });
+          data.push( Vertex { pos: [px + scale, py + scale], col: col.clone() });
+          // Tri 2
+          data.push( Vertex { pos: [px, py], col: col.clone() });
+          data.push( Vertex { pos: [px, py + scale], col: col.clone() });
+          data.push( Vertex { pos: [px + scale, py + scale], col: col.clone() });
+        }
+      }
+      // Leave a pixel gap between glyphs
+      x += (GLYPH_W + 1) as f32 * scale;
+    }
+
+    // Send the data
+    self.sender.send(data).unwrap();
+    x - pos.x
+  }
 }
@@ -9,6 +9,9 @@ mod system;
 /// send data to the renderer.
 mod controller;
 
+/// A module containing the bitmap font used to draw text.
+mod font;
+
 pub use self::system::SysRenderer;
 pub use self::controller::RendererController;
 
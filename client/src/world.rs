@@ -0,0 +1,53 @@
+//! Mirrors the server's world into the ECS, so it's drawn by the render
+//! system.
+
+use std::collections::HashMap;
+use specs::{self, Gate};
+use common::net::SnapshotPacket;
+use component::{CompAABB, CompColor};
+
+/// Keeps an ECS entity for each entity in the server's world.
+pub struct WorldSync {
+  /// The ECS entity for each server entity ID.
+  entities: HashMap<u32, specs::Entity>,
+}
+
+impl WorldSync {
+  pub fn new() -> WorldSync {
+    WorldSync { entities: HashMap::new() }
+  }
+
+  /// Update the ECS to match a snapshot, creating and deleting entities as
+  /// they appear and disappear.
+  pub fn apply(&mut self, world: &mut specs::World, snapshot: &SnapshotPacket) {
+    let removed : Vec<u32> = self.entities.keys().cloned()
+      .filter(|id| !snapshot.entities.iter().any(|e| e.id == *id))
+      .collect();
+    for id in removed {
+      world.delete_now(self.entities.remove(&id).unwrap());
+    }
+
+    for state in &snapshot.entities {
+      match self.entities.get(&state.id) {
+        Some(&e) => {
+          world.write::<CompAABB>().pass().insert(e, CompAABB(state.aabb));
+          world.write::<CompColor>().pass().insert(e, CompColor(state.color));
+        }
+        None => {
+          let e = world.create_now()
+            .with(CompAABB(state.aabb))
+            .with(CompColor(state.color))
+            .build();
+          self.entities.insert(state.id, e);
+        }
+      }
+    }
+  }
+
+  /// Move an entity, such as the predicted local player.
+  pub fn set_aabb(&self, world: &mut specs::World, id: u32, aabb: [f32; 4]) {
+    if let Some(&e) = self.entities.get(&id) {
+      world.write::<CompAABB>().pass().insert(e, CompAABB(aabb));
+    }
+  }
+}
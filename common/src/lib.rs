@@ -1 +1,7 @@
+// Lets code generated by `packet_derive` refer to `::common` from inside
+// this crate too.
+extern crate self as common;
+extern crate packet_derive;
+
 pub mod net;
+pub mod physics;
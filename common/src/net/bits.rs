@@ -0,0 +1,179 @@
+//! Utilities for packing fields smaller than a byte into a packet body. Bits
+//! are written least significant first, and a partially filled final byte is
+//! padded with zeroes.
+
+use net::{DeserialiseError, WireReader};
+
+/// Writes values of arbitrary bit widths, appending the packed bytes to a
+/// buffer. `finish()` must be called to write out the final partial byte.
+pub struct BitWriter<'a> {
+  buf: &'a mut Vec<u8>,
+  /// Bits written but not yet appended to the buffer.
+  scratch: u64,
+  /// The number of valid bits in `scratch`.
+  scratch_bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+  pub fn new(buf: &'a mut Vec<u8>) -> BitWriter<'a> {
+    BitWriter { buf, scratch: 0, scratch_bits: 0 }
+  }
+
+  /// Write the lowest `bits` bits of `value`. `bits` must be at most 32.
+  pub fn write_bits(&mut self, value: u32, bits: u32) {
+    assert!(bits <= 32, "can't write more than 32 bits at once");
+    let mask = (1u64 << bits) - 1;
+    self.scratch |= (value as u64 & mask) << self.scratch_bits;
+    self.scratch_bits += bits;
+    while self.scratch_bits >= 8 {
+      self.buf.push(self.scratch as u8);
+      self.scratch >>= 8;
+      self.scratch_bits -= 8;
+    }
+  }
+
+  pub fn write_bool(&mut self, value: bool) {
+    self.write_bits(value as u32, 1);
+  }
+
+  /// Write an `f32` at full precision.
+  pub fn write_f32(&mut self, value: f32) {
+    self.write_bits(value.to_bits(), 32);
+  }
+
+  /// Flush the final partial byte, if any, to the buffer.
+  pub fn finish(self) {
+    if self.scratch_bits > 0 {
+      self.buf.push(self.scratch as u8);
+    }
+  }
+}
+
+/// Reads values written by a `BitWriter`. Bytes are pulled from the
+/// underlying `WireReader` only as they are needed, so any unread bits in the
+/// last byte are skipped when the `BitReader` is dropped.
+pub struct BitReader<'r, 'a: 'r> {
+  r: &'r mut WireReader<'a>,
+  scratch: u64,
+  scratch_bits: u32,
+}
+
+impl<'r, 'a> BitReader<'r, 'a> {
+  pub fn new(r: &'r mut WireReader<'a>) -> BitReader<'r, 'a> {
+    BitReader { r, scratch: 0, scratch_bits: 0 }
+  }
+
+  /// Read a value `bits` wide. `bits` must be at most 32.
+  pub fn read_bits(&mut self, bits: u32) -> Result<u32, DeserialiseError> {
+    assert!(bits <= 32, "can't read more than 32 bits at once");
+    while self.scratch_bits < bits {
+      let byte = self.r.read_bytes(1)?[0];
+      self.scratch |= (byte as u64) << self.scratch_bits;
+      self.scratch_bits += 8;
+    }
+    let value = self.scratch & ((1u64 << bits) - 1);
+    self.scratch >>= bits;
+    self.scratch_bits -= bits;
+    Ok(value as u32)
+  }
+
+  pub fn read_bool(&mut self) -> Result<bool, DeserialiseError> {
+    Ok(self.read_bits(1)? == 1)
+  }
+
+  /// Read an `f32` written at full precision.
+  pub fn read_f32(&mut self) -> Result<f32, DeserialiseError> {
+    Ok(f32::from_bits(self.read_bits(32)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mixed_widths_round_trip() {
+    let fields : [(u32, u32); 7] = [(1, 1), (5, 3), (0, 0), (0x3ff, 10), (0, 1),
+                                    (0xdead_beef, 32), (0x1_2345, 17)];
+    let mut buf = Vec::new();
+    {
+      let mut w = BitWriter::new(&mut buf);
+      for &(value, bits) in &fields { w.write_bits(value, bits); }
+      w.finish();
+    }
+    // 64 bits, so no padding byte
+    assert_eq!(buf.len(), 8);
+
+    let mut r = WireReader::new(&buf);
+    let mut br = BitReader::new(&mut r);
+    for &(value, bits) in &fields {
+      assert_eq!(br.read_bits(bits).unwrap(), value, "{} bit field", bits);
+    }
+  }
+
+  #[test]
+  fn bits_are_packed_least_significant_first() {
+    let mut buf = Vec::new();
+    {
+      let mut w = BitWriter::new(&mut buf);
+      w.write_bool(true);
+      w.write_bits(0b10, 2);
+      w.write_bits(0x1f, 5);
+      w.write_bool(true);
+      w.finish();
+    }
+    assert_eq!(buf, [0b1111_1101, 0b0000_0001]);
+  }
+
+  #[test]
+  fn values_are_masked_to_their_width() {
+    let mut buf = Vec::new();
+    {
+      let mut w = BitWriter::new(&mut buf);
+      w.write_bits(0xff, 4);
+      w.write_bits(0, 4);
+      w.finish();
+    }
+    assert_eq!(buf, [0x0f]);
+  }
+
+  #[test]
+  fn floats_and_bools_round_trip() {
+    let mut buf = Vec::new();
+    {
+      let mut w = BitWriter::new(&mut buf);
+      w.write_bool(false);
+      w.write_f32(-1.5e-7);
+      w.write_bool(true);
+      w.finish();
+    }
+    let mut r = WireReader::new(&buf);
+    let mut br = BitReader::new(&mut r);
+    assert!(!br.read_bool().unwrap());
+    assert_eq!(br.read_f32().unwrap(), -1.5e-7);
+    assert!(br.read_bool().unwrap());
+  }
+
+  #[test]
+  fn reading_past_the_end_is_truncated() {
+    let buf = [0xffu8];
+    let mut r = WireReader::new(&buf);
+    let mut br = BitReader::new(&mut r);
+    assert_eq!(br.read_bits(6).unwrap(), 0x3f);
+    assert!(matches!(br.read_bits(3), Err(DeserialiseError::Truncated { .. })));
+  }
+
+  #[test]
+  fn reader_leaves_following_bytes_for_the_wire_reader() {
+    let mut buf = Vec::new();
+    {
+      let mut w = BitWriter::new(&mut buf);
+      w.write_bits(3, 2);
+      w.finish();
+    }
+    buf.push(42);
+    let mut r = WireReader::new(&buf);
+    assert_eq!(BitReader::new(&mut r).read_bits(2).unwrap(), 3);
+    assert_eq!(r.read_bytes(1).unwrap(), [42]);
+  }
+}
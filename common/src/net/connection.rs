@@ -0,0 +1,824 @@
+//! A connection layered over UDP, adding sequence numbers and acks to every
+//! datagram. On top of this it offers three channels:
+//!
+//! * `Unreliable` - Messages may be lost, duplicated datagrams are dropped.
+//! * `UnreliableSequenced` - Messages may be lost, and any message older than
+//!   the newest one received is dropped.
+//! * `ReliableOrdered` - Messages are resent until acked, and delivered in the
+//!   order they were sent.
+//!
+//! Messages too large for one datagram are split into fragments. Reliable
+//! fragments are each resent until acked, while partially received unreliable
+//! messages are dropped if the rest of their fragments don't arrive in time.
+//! The number and size of partial messages held is limited, so a peer can't
+//! exhaust our memory.
+//!
+//! The connection doesn't own a socket. Received datagrams are passed to
+//! `receive()`, and datagrams to send are taken from `poll_datagram()`.
+//!
+//! Every datagram starts with the following header, in little-endian:
+//!
+//! ```text
+//! +-----------+-----------+----------------+--------------+---------------------+
+//! | seq (u16) | ack (u16) | ack bits (u32) | channel (u8) | channel seq (u16)   |
+//! +-----------+-----------+----------------+--------------+---------------------+
+//! ```
+//!
+//! `ack` is the newest sequence number received from the peer, and bit `n` of
+//! the ack bits is set if `ack - n - 1` was also received. Until the sender
+//! has received anything, bit 6 of the channel byte is set and the ack fields
+//! are ignored. The channel sequence number is only present for the sequenced
+//! and reliable channels. If the top bit of the channel byte is set, the
+//! datagram is a fragment and the header is followed by the fragment's
+//! message id (u16), index (u8) and the number of fragments in the message
+//! (u8).
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use net::{Wire, WireReader, DeserialiseError, SerialiseError, MAX_DATAGRAM_SIZE};
+
+/// The largest connection header, not including the fragment header, in
+/// bytes.
+pub const CONNECTION_HEADER_SIZE : usize = 11;
+
+/// The largest message payload which fits in a single datagram.
+pub const MAX_PAYLOAD_SIZE : usize = MAX_DATAGRAM_SIZE - CONNECTION_HEADER_SIZE;
+
+/// The size of the fragment header, in bytes.
+pub const FRAGMENT_HEADER_SIZE : usize = 4;
+
+/// The largest part of a message carried by a single fragment.
+pub const FRAGMENT_SIZE : usize = MAX_PAYLOAD_SIZE - FRAGMENT_HEADER_SIZE;
+
+/// The most fragments a message can be split into. This leaves room in the
+/// ack window for the whole message, so a single ack from the peer can cover
+/// every fragment, and none are counted as lost before they could be acked.
+pub const MAX_FRAGMENTS : usize = ACK_WINDOW - 1;
+
+/// The largest message which can be sent, in bytes.
+pub const MAX_MESSAGE_SIZE : usize = FRAGMENT_SIZE * MAX_FRAGMENTS;
+
+/// Bit set in the channel byte of fragments.
+const FRAGMENT_FLAG : u8 = 0x80;
+
+/// Bit set in the channel byte when the sender hasn't received a datagram
+/// yet, so there is nothing to ack.
+const NO_ACKS_FLAG : u8 = 0x40;
+
+/// How long a partially received unreliable message is kept while waiting for
+/// the rest of its fragments.
+const REASSEMBLY_TIMEOUT_MS : u64 = 1000;
+
+/// The most partially received unreliable messages held at once. When a new
+/// one arrives past this limit, the oldest is dropped.
+const MAX_PARTIAL_MESSAGES : usize = 8;
+
+/// How far past the next expected reliable message we buffer messages which
+/// arrived out of order. Anything further ahead is dropped, and will be
+/// resent by the peer.
+const RELIABLE_RECV_WINDOW : u16 = 256;
+
+/// Channel ID used for datagrams which only carry acks.
+const CHANNEL_ACK_ONLY : u8 = 0x3f;
+
+/// The number of sent datagrams remembered while waiting for an ack. A
+/// datagram which falls out of this window without being acked is counted as
+/// lost.
+const ACK_WINDOW : usize = 33;
+
+/// How long to wait before resending a reliable message if the RTT is not yet
+/// known.
+const DEFAULT_RESEND_TIMEOUT_MS : u64 = 100;
+
+/// The lowest resend timeout, regardless of RTT.
+const MIN_RESEND_TIMEOUT_MS : u64 = 20;
+
+/// The weight given to each new sample when smoothing RTT and packet loss.
+const SMOOTHING : f64 = 0.1;
+
+/// The delivery guarantees for a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+  Unreliable,
+  UnreliableSequenced,
+  ReliableOrdered,
+}
+
+impl Channel {
+  fn id(self) -> u8 {
+    match self {
+      Channel::Unreliable => 0,
+      Channel::UnreliableSequenced => 1,
+      Channel::ReliableOrdered => 2,
+    }
+  }
+
+  fn from_id(id: u8) -> Option<Channel> {
+    match id {
+      0 => Some(Channel::Unreliable),
+      1 => Some(Channel::UnreliableSequenced),
+      2 => Some(Channel::ReliableOrdered),
+      _ => None,
+    }
+  }
+}
+
+/// Returns true if sequence number `a` is newer than `b`, accounting for
+/// wrapping.
+pub fn sequence_newer(a: u16, b: u16) -> bool {
+  (a.wrapping_sub(b) as i16) > 0
+}
+
+/// Counters describing the health of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ConnectionStats {
+  pub sent: u64,
+  pub received: u64,
+  pub acked: u64,
+  pub lost: u64,
+  /// Reliable messages sent again after not being acked in time.
+  pub resent: u64,
+}
+
+/// A datagram sent and waiting to be acked.
+struct SentDatagram {
+  seq: u16,
+  time: Instant,
+  acked: bool,
+  /// The id of the reliable message this datagram carried, if any.
+  reliable_id: Option<u16>,
+}
+
+/// Identifies a datagram as one part of a larger message.
+#[derive(Debug, Clone, Copy)]
+struct FragmentHeader {
+  message_id: u16,
+  index: u8,
+  count: u8,
+}
+
+impl Wire for FragmentHeader {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    self.message_id.encode(buf);
+    self.index.encode(buf);
+    self.count.encode(buf);
+  }
+
+  fn decode(r: &mut WireReader) -> Result<FragmentHeader, DeserialiseError> {
+    let offset = r.offset();
+    let header = FragmentHeader {
+      message_id: u16::decode(r)?,
+      index: u8::decode(r)?,
+      count: u8::decode(r)?,
+    };
+    if header.count < 2 || header.count as usize > MAX_FRAGMENTS || header.index >= header.count {
+      return Err(DeserialiseError::InvalidValue { offset, reason: "bad fragment header" });
+    }
+    Ok(header)
+  }
+}
+
+/// A reliable message, or fragment of one, waiting to be acked.
+struct PendingReliable {
+  payload: Vec<u8>,
+  fragment: Option<FragmentHeader>,
+  last_sent: Instant,
+}
+
+/// An unreliable message with only some of its fragments received.
+struct PartialMessage {
+  channel: Channel,
+  fragments: Vec<Option<Vec<u8>>>,
+  /// The number of fragments not yet received.
+  missing: usize,
+  started: Instant,
+}
+
+pub struct Connection {
+  /// The sequence number of the next datagram to send.
+  local_seq: u16,
+  /// The newest sequence number received, and bits for the 32 before it.
+  remote_seq: Option<u16>,
+  remote_ack_bits: u32,
+  /// Set when a datagram needing an ack has been received since we last sent.
+  ack_pending: bool,
+
+  sent: VecDeque<SentDatagram>,
+  outgoing: VecDeque<Vec<u8>>,
+
+  sequenced_send_seq: u16,
+  sequenced_recv_seq: Option<u16>,
+
+  reliable_send_seq: u16,
+  reliable_unacked: BTreeMap<u16, PendingReliable>,
+  reliable_recv_seq: u16,
+  reliable_recv_buf: BTreeMap<u16, (Option<FragmentHeader>, Vec<u8>)>,
+  /// The reliable message being reassembled, and the next fragment index
+  /// expected for it.
+  reliable_partial: Option<(u8, Vec<u8>)>,
+
+  fragment_send_id: u16,
+  partial: HashMap<u16, PartialMessage>,
+
+  rtt: Option<Duration>,
+  packet_loss: f64,
+  stats: ConnectionStats,
+}
+
+impl Connection {
+  pub fn new() -> Connection {
+    Connection {
+      local_seq: 0,
+      remote_seq: None,
+      remote_ack_bits: 0,
+      ack_pending: false,
+      sent: VecDeque::with_capacity(ACK_WINDOW),
+      outgoing: VecDeque::new(),
+      sequenced_send_seq: 0,
+      sequenced_recv_seq: None,
+      reliable_send_seq: 0,
+      reliable_unacked: BTreeMap::new(),
+      reliable_recv_seq: 0,
+      reliable_recv_buf: BTreeMap::new(),
+      reliable_partial: None,
+      fragment_send_id: 0,
+      partial: HashMap::new(),
+      rtt: None,
+      packet_loss: 0.0,
+      stats: ConnectionStats::default(),
+    }
+  }
+
+  /// The smoothed round trip time, once at least one datagram has been acked.
+  pub fn rtt(&self) -> Option<Duration> {
+    self.rtt
+  }
+
+  /// The smoothed fraction of datagrams lost, from 0 to 1.
+  pub fn packet_loss(&self) -> f64 {
+    self.packet_loss
+  }
+
+  pub fn stats(&self) -> ConnectionStats {
+    self.stats
+  }
+
+  /// Queue a message to be sent, splitting it into fragments if it doesn't
+  /// fit in a single datagram.
+  /// # Returns
+  /// `SerialiseError::FrameTooLarge` if the payload is larger than
+  /// `MAX_MESSAGE_SIZE`.
+  pub fn send(&mut self, channel: Channel, payload: &[u8], now: Instant) -> Result<(), SerialiseError> {
+    if payload.len() > MAX_MESSAGE_SIZE {
+      return Err(SerialiseError::FrameTooLarge { size: payload.len(), max: MAX_MESSAGE_SIZE });
+    }
+    // Every fragment of a sequenced message shares its sequence number
+    let sequenced_seq = if channel == Channel::UnreliableSequenced {
+      let seq = self.sequenced_send_seq;
+      self.sequenced_send_seq = seq.wrapping_add(1);
+      Some(seq)
+    } else {
+      None
+    };
+
+    if payload.len() <= MAX_PAYLOAD_SIZE {
+      self.send_message(channel, sequenced_seq, None, payload, now);
+    } else {
+      let message_id = self.fragment_send_id;
+      self.fragment_send_id = message_id.wrapping_add(1);
+      let count = payload.len().div_ceil(FRAGMENT_SIZE) as u8;
+      for (index, chunk) in payload.chunks(FRAGMENT_SIZE).enumerate() {
+        let fragment = FragmentHeader { message_id, index: index as u8, count };
+        self.send_message(channel, sequenced_seq, Some(fragment), chunk, now);
+      }
+    }
+    Ok(())
+  }
+
+  /// Send a whole message, or a single fragment.
+  fn send_message(&mut self, channel: Channel, sequenced_seq: Option<u16>,
+                  fragment: Option<FragmentHeader>, payload: &[u8], now: Instant) {
+    if channel == Channel::ReliableOrdered {
+      let id = self.reliable_send_seq;
+      self.reliable_send_seq = id.wrapping_add(1);
+      self.reliable_unacked.insert(id, PendingReliable {
+        payload: payload.to_vec(), fragment, last_sent: now });
+      self.send_datagram(channel, Some(id), fragment, payload, now);
+    } else {
+      self.send_datagram(channel, sequenced_seq, fragment, payload, now);
+    }
+  }
+
+  /// Resend reliable messages which haven't been acked in time, and send an
+  /// ack if one is owed. Should be called regularly.
+  pub fn update(&mut self, now: Instant) {
+    let timeout = self.resend_timeout();
+    let due : Vec<u16> = self.reliable_unacked.iter()
+      .filter(|&(_, m)| now.duration_since(m.last_sent) >= timeout)
+      .map(|(&id, _)| id)
+      .collect();
+    for id in due {
+      let (fragment, payload) = {
+        let m = self.reliable_unacked.get_mut(&id).unwrap();
+        m.last_sent = now;
+        (m.fragment, m.payload.clone())
+      };
+      self.stats.resent += 1;
+      self.send_datagram(Channel::ReliableOrdered, Some(id), fragment, &payload, now);
+    }
+    self.expire_partial_messages(now);
+
+    if self.ack_pending {
+      let header = self.header(CHANNEL_ACK_ONLY, None);
+      self.outgoing.push_back(header);
+    }
+  }
+
+  /// Take the next datagram to be written to the socket.
+  pub fn poll_datagram(&mut self) -> Option<Vec<u8>> {
+    self.outgoing.pop_front()
+  }
+
+  /// Process a datagram received from the peer.
+  /// # Returns
+  /// The messages ready for delivery, or an error if the datagram was
+  /// malformed.
+  pub fn receive(&mut self, datagram: &[u8], now: Instant)
+      -> Result<Vec<(Channel, Vec<u8>)>, DeserialiseError> {
+    let mut r = WireReader::new(datagram);
+    let seq = u16::decode(&mut r)?;
+    let ack = u16::decode(&mut r)?;
+    let ack_bits = u32::decode(&mut r)?;
+    let channel_offset = r.offset();
+    let channel_byte = u8::decode(&mut r)?;
+    let channel_id = channel_byte & !(FRAGMENT_FLAG | NO_ACKS_FLAG);
+
+    if channel_byte & NO_ACKS_FLAG == 0 { self.process_acks(ack, ack_bits, now); }
+    if channel_id == CHANNEL_ACK_ONLY {
+      r.finish()?;
+      return Ok(Vec::new());
+    }
+    let channel = Channel::from_id(channel_id).ok_or(DeserialiseError::InvalidValue {
+      offset: channel_offset, reason: "unknown channel" })?;
+    let channel_seq = if channel == Channel::Unreliable { None } else { Some(u16::decode(&mut r)?) };
+    let fragment = if channel_byte & FRAGMENT_FLAG != 0 {
+      Some(FragmentHeader::decode(&mut r)?)
+    } else {
+      None
+    };
+    let payload = r.read_bytes(r.remaining())?.to_vec();
+
+    if !self.record_received(seq) { return Ok(Vec::new()); }
+    self.stats.received += 1;
+    self.ack_pending = true;
+
+    let mut ret = Vec::new();
+    match (channel, channel_seq) {
+      (Channel::ReliableOrdered, Some(id)) => self.receive_reliable(id, fragment, payload, &mut ret),
+      _ => {
+        let message = match fragment {
+          Some(fragment) => self.reassemble(channel, fragment, payload, now),
+          None => Some(payload),
+        };
+        if let Some(message) = message {
+          match channel_seq {
+            Some(seq) if self.sequenced_recv_seq.is_some_and(|latest| !sequence_newer(seq, latest)) => (),
+            _ => {
+              if channel_seq.is_some() { self.sequenced_recv_seq = channel_seq; }
+              ret.push((channel, message));
+            }
+          }
+        }
+      }
+    }
+    Ok(ret)
+  }
+
+  /// Buffer a reliable message, then deliver every message which is now in
+  /// order, joining fragments back together.
+  fn receive_reliable(&mut self, id: u16, fragment: Option<FragmentHeader>, payload: Vec<u8>,
+                      ret: &mut Vec<(Channel, Vec<u8>)>) {
+    if id.wrapping_sub(self.reliable_recv_seq) < RELIABLE_RECV_WINDOW {
+      self.reliable_recv_buf.insert(id, (fragment, payload));
+    }
+    while let Some((fragment, payload)) = self.reliable_recv_buf.remove(&self.reliable_recv_seq) {
+      self.reliable_recv_seq = self.reliable_recv_seq.wrapping_add(1);
+      let fragment = match fragment {
+        Some(fragment) => fragment,
+        None => {
+          ret.push((Channel::ReliableOrdered, payload));
+          continue;
+        }
+      };
+      let partial = if fragment.index == 0 { Some((0, Vec::new())) } else { self.reliable_partial.take() };
+      // Fragments arrive in order, so anything else means the peer is
+      // misbehaving and the fragment is dropped.
+      if let Some((next, mut buf)) = partial {
+        if next != fragment.index { continue; }
+        buf.extend_from_slice(&payload);
+        if fragment.index + 1 == fragment.count {
+          ret.push((Channel::ReliableOrdered, buf));
+        } else {
+          self.reliable_partial = Some((next + 1, buf));
+        }
+      }
+    }
+  }
+
+  /// Store a fragment of an unreliable message.
+  /// # Returns
+  /// The whole message, once every fragment has arrived.
+  fn reassemble(&mut self, channel: Channel, fragment: FragmentHeader, payload: Vec<u8>,
+                now: Instant) -> Option<Vec<u8>> {
+    self.expire_partial_messages(now);
+    if !self.partial.contains_key(&fragment.message_id) && self.partial.len() >= MAX_PARTIAL_MESSAGES {
+      let oldest = self.partial.iter().min_by_key(|&(_, p)| p.started).map(|(&id, _)| id);
+      if let Some(oldest) = oldest { self.partial.remove(&oldest); }
+    }
+
+    let complete = {
+      let partial = self.partial.entry(fragment.message_id).or_insert_with(|| PartialMessage {
+        channel,
+        fragments: vec![None; fragment.count as usize],
+        missing: fragment.count as usize,
+        started: now,
+      });
+      if partial.channel != channel || partial.fragments.len() != fragment.count as usize {
+        return None;
+      }
+      let slot = &mut partial.fragments[fragment.index as usize];
+      if slot.is_none() {
+        *slot = Some(payload);
+        partial.missing -= 1;
+      }
+      partial.missing == 0
+    };
+    if !complete { return None; }
+
+    let partial = self.partial.remove(&fragment.message_id).unwrap();
+    Some(partial.fragments.into_iter().flatten().flatten().collect())
+  }
+
+  /// Drop partially received messages which have waited too long.
+  fn expire_partial_messages(&mut self, now: Instant) {
+    let timeout = Duration::from_millis(REASSEMBLY_TIMEOUT_MS);
+    self.partial.retain(|_, p| now.duration_since(p.started) < timeout);
+  }
+
+  /// Build the header for the next datagram, advancing the local sequence
+  /// number. Sending any datagram acks everything received so far.
+  fn header(&mut self, channel_id: u8, channel_seq: Option<u16>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    self.local_seq.encode(&mut buf);
+    self.remote_seq.unwrap_or(0).encode(&mut buf);
+    self.remote_ack_bits.encode(&mut buf);
+    let flags = if self.remote_seq.is_none() { NO_ACKS_FLAG } else { 0 };
+    (channel_id | flags).encode(&mut buf);
+    if let Some(channel_seq) = channel_seq { channel_seq.encode(&mut buf); }
+    self.local_seq = self.local_seq.wrapping_add(1);
+    self.ack_pending = false;
+    buf
+  }
+
+  fn send_datagram(&mut self, channel: Channel, channel_seq: Option<u16>,
+                   fragment: Option<FragmentHeader>, payload: &[u8], now: Instant) {
+    let seq = self.local_seq;
+    let channel_byte = if fragment.is_some() { channel.id() | FRAGMENT_FLAG } else { channel.id() };
+    let mut datagram = self.header(channel_byte, channel_seq);
+    if let Some(fragment) = fragment { fragment.encode(&mut datagram); }
+    datagram.extend_from_slice(payload);
+    self.outgoing.push_back(datagram);
+    self.stats.sent += 1;
+
+    if self.sent.len() == ACK_WINDOW {
+      let oldest = self.sent.pop_front().unwrap();
+      if !oldest.acked { self.record_loss(true); }
+    }
+    let reliable_id = if channel == Channel::ReliableOrdered { channel_seq } else { None };
+    self.sent.push_back(SentDatagram { seq, time: now, acked: false, reliable_id });
+  }
+
+  /// Update the received sequence numbers with a new datagram.
+  /// # Returns
+  /// False if the datagram is a duplicate or too old to be acked.
+  fn record_received(&mut self, seq: u16) -> bool {
+    let remote_seq = match self.remote_seq {
+      None => {
+        self.remote_seq = Some(seq);
+        return true;
+      }
+      Some(remote_seq) => remote_seq,
+    };
+    if sequence_newer(seq, remote_seq) {
+      // The previous newest datagram becomes bit `shift - 1`, and anything
+      // shifted past bit 31 is forgotten.
+      let shift = seq.wrapping_sub(remote_seq) as u32;
+      let bits = (self.remote_ack_bits as u64).checked_shl(shift).unwrap_or(0);
+      self.remote_ack_bits = (bits | 1u64.checked_shl(shift - 1).unwrap_or(0)) as u32;
+      self.remote_seq = Some(seq);
+      true
+    } else {
+      let distance = remote_seq.wrapping_sub(seq) as u32;
+      if distance == 0 || distance > 32 { return false; }
+      let bit = 1 << (distance - 1);
+      if self.remote_ack_bits & bit != 0 { return false; }
+      self.remote_ack_bits |= bit;
+      true
+    }
+  }
+
+  /// Mark sent datagrams as acked, updating RTT and acking any reliable
+  /// messages they carried.
+  fn process_acks(&mut self, ack: u16, ack_bits: u32, now: Instant) {
+    let mut newly_acked = Vec::new();
+    for sent in self.sent.iter_mut().filter(|s| !s.acked) {
+      let distance = ack.wrapping_sub(sent.seq) as u32;
+      let acked = distance == 0 || (distance <= 32 && ack_bits & (1 << (distance - 1)) != 0);
+      if acked {
+        sent.acked = true;
+        newly_acked.push((now.duration_since(sent.time), sent.reliable_id));
+      }
+    }
+    for (sample, reliable_id) in newly_acked {
+      self.stats.acked += 1;
+      self.record_loss(false);
+      self.rtt = Some(match self.rtt {
+        None => sample,
+        Some(rtt) => rtt.mul_f64(1.0 - SMOOTHING) + sample.mul_f64(SMOOTHING),
+      });
+      if let Some(id) = reliable_id { self.reliable_unacked.remove(&id); }
+    }
+  }
+
+  fn record_loss(&mut self, lost: bool) {
+    if lost { self.stats.lost += 1; }
+    let sample = if lost { 1.0 } else { 0.0 };
+    self.packet_loss += (sample - self.packet_loss) * SMOOTHING;
+  }
+
+  fn resend_timeout(&self) -> Duration {
+    match self.rtt {
+      Some(rtt) => rtt.mul_f64(1.5).max(Duration::from_millis(MIN_RESEND_TIMEOUT_MS)),
+      None => Duration::from_millis(DEFAULT_RESEND_TIMEOUT_MS),
+    }
+  }
+}
+
+impl Default for Connection {
+  fn default() -> Connection {
+    Connection::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+  }
+
+  fn datagrams(c: &mut Connection) -> Vec<Vec<u8>> {
+    let mut ret = Vec::new();
+    while let Some(d) = c.poll_datagram() { ret.push(d); }
+    ret
+  }
+
+  /// Deliver datagrams, returning the payloads of the messages received.
+  fn deliver(to: &mut Connection, datagrams: &[Vec<u8>], now: Instant) -> Vec<Vec<u8>> {
+    let mut ret = Vec::new();
+    for d in datagrams {
+      ret.extend(to.receive(d, now).unwrap().into_iter().map(|(_, m)| m));
+    }
+    ret
+  }
+
+  #[test]
+  fn nothing_is_acked_before_anything_is_received() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    a.send(Channel::ReliableOrdered, b"lost", now).unwrap();
+    datagrams(&mut a);
+
+    // B has received nothing, so its first datagram mustn't ack A's seq 0
+    b.send(Channel::Unreliable, b"hi", now).unwrap();
+    assert_eq!(deliver(&mut a, &datagrams(&mut b), now), vec![b"hi".to_vec()]);
+    assert_eq!(a.stats().acked, 0);
+
+    a.update(now + ms(DEFAULT_RESEND_TIMEOUT_MS));
+    assert_eq!(deliver(&mut b, &datagrams(&mut a), now), vec![b"lost".to_vec()]);
+  }
+
+  #[test]
+  fn ack_bits_cover_the_previous_32_datagrams() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    for i in 0..33u8 { a.send(Channel::Unreliable, &[i], now).unwrap(); }
+    let mut sent = datagrams(&mut a);
+    sent.remove(5);
+    assert_eq!(deliver(&mut b, &sent, now).len(), 32);
+    assert_eq!(b.remote_seq, Some(32));
+    assert_eq!(b.remote_ack_bits, !(1 << (32 - 5 - 1)));
+
+    b.update(now);
+    deliver(&mut a, &datagrams(&mut b), now);
+    assert_eq!(a.stats().acked, 32);
+    assert!(a.sent.iter().all(|s| s.acked == (s.seq != 5)));
+  }
+
+  #[test]
+  fn duplicates_and_very_old_datagrams_are_dropped() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    for i in 0..40u8 { a.send(Channel::Unreliable, &[i], now).unwrap(); }
+    let sent = datagrams(&mut a);
+    assert_eq!(deliver(&mut b, &sent[1..], now).len(), 39);
+    // A duplicate, and one more than 32 behind the newest
+    assert!(deliver(&mut b, &sent[20..21], now).is_empty());
+    assert!(deliver(&mut b, &sent[..1], now).is_empty());
+    assert_eq!(b.stats().received, 39);
+  }
+
+  #[test]
+  fn rtt_is_smoothed_from_acked_datagrams() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    assert_eq!(a.rtt(), None);
+
+    a.send(Channel::Unreliable, b"1", now).unwrap();
+    deliver(&mut b, &datagrams(&mut a), now);
+    b.update(now);
+    deliver(&mut a, &datagrams(&mut b), now + ms(50));
+    assert_eq!(a.rtt(), Some(ms(50)));
+
+    a.send(Channel::Unreliable, b"2", now + ms(100)).unwrap();
+    deliver(&mut b, &datagrams(&mut a), now);
+    b.update(now);
+    deliver(&mut a, &datagrams(&mut b), now + ms(250));
+    assert_eq!(a.rtt(), Some(ms(60)));
+  }
+
+  #[test]
+  fn unacked_datagrams_leaving_the_window_are_lost() {
+    let now = Instant::now();
+    let mut a = Connection::new();
+    for _ in 0..ACK_WINDOW + 7 { a.send(Channel::Unreliable, b"x", now).unwrap(); }
+    assert_eq!(a.stats().lost, 7);
+    assert!(a.packet_loss() > 0.5);
+  }
+
+  #[test]
+  fn reliable_messages_arrive_in_order_despite_loss_and_reordering() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    for i in 0..5u8 { a.send(Channel::ReliableOrdered, &[i], now).unwrap(); }
+    let mut sent = datagrams(&mut a);
+    sent.remove(2);
+    sent.reverse();
+    // Everything after the lost message waits for it
+    assert_eq!(deliver(&mut b, &sent, now), vec![vec![0], vec![1]]);
+
+    b.update(now);
+    deliver(&mut a, &datagrams(&mut b), now);
+    assert_eq!(a.reliable_unacked.keys().cloned().collect::<Vec<_>>(), vec![2]);
+    a.update(now + ms(DEFAULT_RESEND_TIMEOUT_MS));
+    let resent = datagrams(&mut a);
+    assert_eq!(deliver(&mut b, &resent, now), vec![vec![2], vec![3], vec![4]]);
+    assert_eq!(a.stats().resent, 1);
+  }
+
+  #[test]
+  fn sequenced_messages_older_than_the_newest_are_dropped() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    for i in 0..3u8 { a.send(Channel::UnreliableSequenced, &[i], now).unwrap(); }
+    let sent = datagrams(&mut a);
+    assert_eq!(deliver(&mut b, &[sent[0].clone(), sent[2].clone(), sent[1].clone()], now),
+               vec![vec![0], vec![2]]);
+  }
+
+  #[test]
+  fn sequence_numbers_wrap_around() {
+    assert!(sequence_newer(0, 65535));
+    assert!(sequence_newer(10, 65530));
+    assert!(!sequence_newer(65530, 10));
+
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    a.local_seq = 65530;
+    a.reliable_send_seq = 65533;
+    b.reliable_recv_seq = 65533;
+    for i in 0..10u8 { a.send(Channel::ReliableOrdered, &[i], now).unwrap(); }
+    let mut sent = datagrams(&mut a);
+    sent.swap(4, 7);
+    let received = deliver(&mut b, &sent, now);
+    assert_eq!(received, (0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+    assert_eq!(b.remote_seq, Some(3));
+    assert_eq!(b.remote_ack_bits & 0x1ff, 0x1ff);
+
+    b.update(now);
+    deliver(&mut a, &datagrams(&mut b), now);
+    assert_eq!(a.stats().acked, 10);
+    assert!(a.reliable_unacked.is_empty());
+  }
+
+  fn message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7) as u8).collect()
+  }
+
+  #[test]
+  fn large_messages_are_split_and_reassembled_out_of_order() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    let big = message(FRAGMENT_SIZE * 3 + 10);
+    a.send(Channel::Unreliable, &big, now).unwrap();
+    let mut sent = datagrams(&mut a);
+    assert_eq!(sent.len(), 4);
+    assert!(sent.iter().all(|d| d.len() <= MAX_DATAGRAM_SIZE));
+
+    sent.swap(0, 3);
+    assert!(deliver(&mut b, &sent[..3], now).is_empty());
+    // A duplicate fragment doesn't complete the message
+    assert!(deliver(&mut b, &sent[..1], now).is_empty());
+    assert_eq!(deliver(&mut b, &sent[3..], now), vec![big]);
+    assert!(b.partial.is_empty());
+  }
+
+  #[test]
+  fn partial_messages_time_out() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    a.send(Channel::Unreliable, &message(MAX_PAYLOAD_SIZE + 1), now).unwrap();
+    let sent = datagrams(&mut a);
+    deliver(&mut b, &sent[..1], now);
+    assert_eq!(b.partial.len(), 1);
+    b.update(now + ms(REASSEMBLY_TIMEOUT_MS));
+    assert!(b.partial.is_empty());
+    // The rest of the message arriving late starts a new partial message
+    assert!(deliver(&mut b, &sent[1..], now + ms(REASSEMBLY_TIMEOUT_MS)).is_empty());
+  }
+
+  #[test]
+  fn partial_messages_are_limited() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    let mut last = Vec::new();
+    for i in 0..MAX_PARTIAL_MESSAGES as u64 + 2 {
+      a.send(Channel::Unreliable, &message(MAX_PAYLOAD_SIZE + 1), now).unwrap();
+      let sent = datagrams(&mut a);
+      deliver(&mut b, &sent[..1], now + ms(i));
+      last = sent;
+    }
+    assert_eq!(b.partial.len(), MAX_PARTIAL_MESSAGES);
+    // The oldest were dropped, and the newest can still complete
+    assert!(!b.partial.contains_key(&0) && !b.partial.contains_key(&1));
+    assert_eq!(deliver(&mut b, &last[1..], now + ms(20)).len(), 1);
+  }
+
+  #[test]
+  fn oversized_messages_and_bad_fragment_headers_are_rejected() {
+    let now = Instant::now();
+    let mut a = Connection::new();
+    assert!(a.send(Channel::ReliableOrdered, &message(MAX_MESSAGE_SIZE + 1), now).is_err());
+
+    let mut b = Connection::new();
+    let mut datagram = b.header(Channel::Unreliable.id() | FRAGMENT_FLAG, None);
+    FragmentHeader { message_id: 0, index: 0, count: MAX_FRAGMENTS as u8 + 1 }.encode(&mut datagram);
+    assert!(a.receive(&datagram, now).is_err());
+  }
+
+  #[test]
+  fn largest_reliable_message_is_acked_in_one_reply() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    let big = message(MAX_MESSAGE_SIZE);
+    a.send(Channel::ReliableOrdered, &big, now).unwrap();
+    let sent = datagrams(&mut a);
+    assert_eq!(sent.len(), MAX_FRAGMENTS);
+    assert_eq!(deliver(&mut b, &sent, now), vec![big]);
+
+    b.update(now);
+    deliver(&mut a, &datagrams(&mut b), now + ms(10));
+    assert!(a.reliable_unacked.is_empty());
+    assert_eq!(a.stats().lost, 0);
+    a.update(now + ms(1000));
+    assert!(a.poll_datagram().is_none());
+  }
+
+  #[test]
+  fn lost_reliable_fragments_are_resent() {
+    let now = Instant::now();
+    let (mut a, mut b) = (Connection::new(), Connection::new());
+    let big = message(FRAGMENT_SIZE * 2 + 1);
+    a.send(Channel::ReliableOrdered, &big, now).unwrap();
+    let sent = datagrams(&mut a);
+    assert!(deliver(&mut b, &[sent[0].clone(), sent[2].clone()], now).is_empty());
+
+    b.update(now);
+    deliver(&mut a, &datagrams(&mut b), now);
+    a.update(now + ms(DEFAULT_RESEND_TIMEOUT_MS));
+    let resent = datagrams(&mut a);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(deliver(&mut b, &resent, now), vec![big]);
+  }
+}
@@ -0,0 +1,249 @@
+//! Length-prefixed framing for packets sent over a byte stream.
+//!
+//! Every frame on the wire is laid out as follows, with all integers in
+//! little-endian byte order:
+//!
+//! ```text
+//! +-------------------+---------------+----------------------+
+//! | body length (u32) | tag (3 bytes) | body (length bytes)  |
+//! +-------------------+---------------+----------------------+
+//! ```
+//!
+//! The length only counts the body, not the 7 byte header.
+
+use net::{Packet, Tag, DeserialiseError, SerialiseError, MAX_PAYLOAD_SIZE, MAX_MESSAGE_SIZE};
+
+/// The size of the frame header in bytes - 4 bytes of length, 3 bytes of tag.
+pub const FRAME_HEADER_SIZE : usize = 7;
+
+/// The largest UDP datagram we send, in bytes. This keeps datagrams under the
+/// MTU of most links, avoiding IP fragmentation.
+pub const MAX_DATAGRAM_SIZE : usize = 1200;
+
+/// The default maximum body size of a frame in bytes.
+pub const DEFAULT_MAX_FRAME_SIZE : usize = 64 * 1024;
+
+/// A single frame, split into its tag and body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+  /// The tag identifying the type of packet contained in the body.
+  pub tag: Tag,
+  /// The body of the frame, stripped of the header.
+  pub body: Vec<u8>,
+}
+
+impl Frame {
+  /// Decode a message which holds exactly one frame, such as a message
+  /// received through a `Connection`.
+  pub fn from_message(message: &[u8]) -> Result<Frame, DeserialiseError> {
+    let mut decoder = FrameDecoder::new(MAX_MESSAGE_SIZE);
+    decoder.push(message);
+    match decoder.next_frame()? {
+      Some(frame) if decoder.buffered() == 0 => Ok(frame),
+      Some(frame) => Err(DeserialiseError::TrailingBytes {
+        offset: FRAME_HEADER_SIZE + frame.body.len(), count: decoder.buffered() }),
+      None => Err(DeserialiseError::Truncated {
+        offset: 0, needed: FRAME_HEADER_SIZE, available: message.len() }),
+    }
+  }
+}
+
+/// Turns packets into framed bytes ready to be written to a stream.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameEncoder {
+  max_frame_size: usize,
+}
+
+impl FrameEncoder {
+  /// Create a new encoder.
+  /// # Params
+  /// * `max_frame_size` - The largest body size in bytes to produce.
+  pub fn new(max_frame_size: usize) -> FrameEncoder {
+    FrameEncoder { max_frame_size }
+  }
+
+  /// Create an encoder which only produces frames that fit in a single UDP
+  /// datagram, after the connection header.
+  pub fn datagram() -> FrameEncoder {
+    FrameEncoder::new(MAX_PAYLOAD_SIZE - FRAME_HEADER_SIZE)
+  }
+
+  /// Create an encoder which produces frames up to the largest message a
+  /// `Connection` can send, fragmenting it if needed.
+  pub fn message() -> FrameEncoder {
+    FrameEncoder::new(MAX_MESSAGE_SIZE - FRAME_HEADER_SIZE)
+  }
+
+  /// Serialise a packet and wrap it in a frame.
+  pub fn encode<P: Packet>(&self, packet: &P) -> Result<Vec<u8>, SerialiseError> {
+    let mut body = Vec::new();
+    packet.serialise_body(&mut body);
+    self.encode_raw(P::TAG, &body)
+  }
+
+  /// Wrap an already serialised body in a frame.
+  pub fn encode_raw(&self, tag: Tag, body: &[u8]) -> Result<Vec<u8>, SerialiseError> {
+    if body.len() > self.max_frame_size {
+      return Err(SerialiseError::FrameTooLarge { size: body.len(), max: self.max_frame_size });
+    }
+    let mut ret = Vec::with_capacity(FRAME_HEADER_SIZE + body.len());
+    ret.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    ret.extend_from_slice(&tag);
+    ret.extend_from_slice(body);
+    Ok(ret)
+  }
+}
+
+impl Default for FrameEncoder {
+  fn default() -> FrameEncoder {
+    FrameEncoder::new(DEFAULT_MAX_FRAME_SIZE)
+  }
+}
+
+/// A streaming decoder. Arbitrary chunks of bytes are pushed in as they
+/// arrive, and complete frames are pulled out once all their bytes are
+/// available.
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+  /// Bytes received but not yet consumed as part of a frame.
+  buf: Vec<u8>,
+  max_frame_size: usize,
+}
+
+impl FrameDecoder {
+  /// Create a new decoder.
+  /// # Params
+  /// * `max_frame_size` - The largest body size in bytes to accept. Anything
+  ///   larger is reported as an error before the body is buffered.
+  pub fn new(max_frame_size: usize) -> FrameDecoder {
+    FrameDecoder { buf: Vec::new(), max_frame_size }
+  }
+
+  /// Append bytes received from the stream.
+  pub fn push(&mut self, bytes: &[u8]) {
+    self.buf.extend_from_slice(bytes);
+  }
+
+  /// The number of bytes buffered but not yet decoded into a frame.
+  pub fn buffered(&self) -> usize {
+    self.buf.len()
+  }
+
+  /// Try to decode the next frame.
+  /// # Returns
+  /// `Ok(None)` if there are not yet enough bytes for a full frame, or an
+  /// error if the next frame declares a body larger than the maximum frame
+  /// size. The stream can't be resynchronised after an error, so the
+  /// connection should be dropped.
+  pub fn next_frame(&mut self) -> Result<Option<Frame>, DeserialiseError> {
+    if self.buf.len() < FRAME_HEADER_SIZE { return Ok(None); }
+
+    let size = u32::from_le_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]) as usize;
+    if size > self.max_frame_size {
+      return Err(DeserialiseError::FrameTooLarge { size, max: self.max_frame_size });
+    }
+    if self.buf.len() - FRAME_HEADER_SIZE < size { return Ok(None); }
+
+    let tag = [self.buf[4], self.buf[5], self.buf[6]];
+    let body = self.buf[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + size].to_vec();
+    self.buf.drain(..FRAME_HEADER_SIZE + size);
+    Ok(Some(Frame { tag, body }))
+  }
+}
+
+impl Default for FrameDecoder {
+  fn default() -> FrameDecoder {
+    FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use net::{RegAckPacket, TAG_REG_ACK};
+
+  fn frame(tag: &[u8; 3], body: &[u8]) -> Vec<u8> {
+    FrameEncoder::default().encode_raw(*tag, body).unwrap()
+  }
+
+  #[test]
+  fn header_is_little_endian() {
+    let bytes = frame(b"abc", &[9; 258]);
+    assert_eq!(&bytes[..FRAME_HEADER_SIZE], &[2, 1, 0, 0, b'a', b'b', b'c']);
+    assert_eq!(bytes.len(), FRAME_HEADER_SIZE + 258);
+  }
+
+  #[test]
+  fn encoded_packet_decodes() {
+    let packet = RegAckPacket { client_id: 65537, session_token: 0x0123_4567_89ab_cdef };
+    let mut decoder = FrameDecoder::default();
+    decoder.push(&FrameEncoder::default().encode(&packet).unwrap());
+    let frame = decoder.next_frame().unwrap().unwrap();
+    assert_eq!(frame.tag, TAG_REG_ACK);
+    assert_eq!(RegAckPacket::deserialise(&frame.body).unwrap(), packet);
+    assert_eq!(decoder.buffered(), 0);
+  }
+
+  #[test]
+  fn partial_frames_wait_for_more_bytes() {
+    let bytes = frame(b"abc", b"hello");
+    let mut decoder = FrameDecoder::default();
+    // A byte at a time, through the header and into the body
+    for &b in &bytes[..bytes.len() - 1] {
+      decoder.push(&[b]);
+      assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+    decoder.push(&bytes[bytes.len() - 1..]);
+    assert_eq!(decoder.next_frame().unwrap(),
+               Some(Frame { tag: *b"abc", body: b"hello".to_vec() }));
+    assert_eq!(decoder.next_frame().unwrap(), None);
+  }
+
+  #[test]
+  fn multiple_frames_in_one_read() {
+    let mut bytes = frame(b"one", b"first");
+    bytes.extend(frame(b"two", b""));
+    bytes.extend(frame(b"thr", b"third"));
+    // The start of a fourth frame, split mid header
+    bytes.extend(&frame(b"fou", b"fourth")[..3]);
+
+    let mut decoder = FrameDecoder::default();
+    decoder.push(&bytes);
+    assert_eq!(decoder.next_frame().unwrap().unwrap().body, b"first");
+    assert_eq!(decoder.next_frame().unwrap().unwrap().tag, *b"two");
+    assert_eq!(decoder.next_frame().unwrap().unwrap().body, b"third");
+    assert_eq!(decoder.next_frame().unwrap(), None);
+    assert_eq!(decoder.buffered(), 3);
+  }
+
+  #[test]
+  fn oversized_length_is_rejected_before_the_body() {
+    let mut decoder = FrameDecoder::new(16);
+    decoder.push(&frame(b"big", &[0; 17])[..FRAME_HEADER_SIZE]);
+    assert_eq!(decoder.next_frame(), Err(DeserialiseError::FrameTooLarge { size: 17, max: 16 }));
+
+    // A corrupt length is caught the same way
+    let mut decoder = FrameDecoder::default();
+    decoder.push(&[0xff, 0xff, 0xff, 0xff, b'b', b'a', b'd']);
+    assert!(decoder.next_frame().is_err());
+  }
+
+  #[test]
+  fn encoder_enforces_its_maximum() {
+    let encoder = FrameEncoder::new(4);
+    assert!(encoder.encode_raw(*b"abc", &[0; 4]).is_ok());
+    assert_eq!(encoder.encode_raw(*b"abc", &[0; 5]),
+               Err(SerialiseError::FrameTooLarge { size: 5, max: 4 }));
+  }
+
+  #[test]
+  fn message_must_hold_exactly_one_frame() {
+    let bytes = frame(b"abc", b"body");
+    assert_eq!(Frame::from_message(&bytes).unwrap().body, b"body");
+    assert!(matches!(Frame::from_message(&bytes[..5]), Err(DeserialiseError::Truncated { .. })));
+    let mut two = bytes.clone();
+    two.extend(&bytes);
+    assert_eq!(Frame::from_message(&two),
+               Err(DeserialiseError::TrailingBytes { offset: 11, count: 11 }));
+  }
+}
@@ -1,3 +1,16 @@
 mod packet;
+mod frame;
+mod wire;
+mod bits;
+mod quantize;
+mod connection;
+mod version;
 
 pub use self::packet::*;
+pub use self::frame::*;
+pub use self::wire::*;
+pub use self::bits::*;
+pub use self::quantize::*;
+pub use self::connection::*;
+pub use self::version::*;
+pub use packet_derive::{Packet, Wire};
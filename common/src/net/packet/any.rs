@@ -0,0 +1,116 @@
+//! A registry of every packet type, used to decode a frame into the right
+//! `Packet` implementor based on its tag.
+
+use net::{Packet, Tag, Frame, DeserialiseError};
+use net::packet::*;
+
+/// Declares the `AnyPacket` enum, with one variant per packet type. Adding a
+/// new packet only requires adding a line here.
+macro_rules! packet_registry {
+  ($($variant:ident($packet:ty)),* $(,)*) => {
+    /// Any packet which can be sent over the network.
+    pub enum AnyPacket {
+      $($variant($packet)),*
+    }
+
+    impl AnyPacket {
+      /// Decode a frame body into the packet type identified by `tag`.
+      /// # Returns
+      /// `DeserialiseError::UnknownTag` if no packet is registered with the
+      /// given tag, otherwise the result of the packet's deserialisation.
+      pub fn decode(tag: Tag, body: &[u8]) -> Result<AnyPacket, DeserialiseError> {
+        $(
+          if tag == <$packet as Packet>::TAG {
+            return <$packet as Packet>::deserialise(body).map(AnyPacket::$variant);
+          }
+        )*
+        Err(DeserialiseError::UnknownTag(tag))
+      }
+
+      /// The tag of the contained packet.
+      pub fn tag(&self) -> Tag {
+        match *self {
+          $(AnyPacket::$variant(_) => <$packet as Packet>::TAG),*
+        }
+      }
+    }
+  }
+}
+
+packet_registry! {
+  Hello(HelloPacket),
+  HelloAck(HelloAckPacket),
+  HelloReject(HelloRejectPacket),
+  Reg(RegPacket),
+  RegAck(RegAckPacket),
+  RegReject(RegRejectPacket),
+  UdpHello(UdpHelloPacket),
+  GameJoin(GameJoinPacket),
+  GameJoinAck(GameJoinAckPacket),
+  GameJoinReject(GameJoinRejectPacket),
+  GameListRequest(GameListRequestPacket),
+  GameList(GameListPacket),
+  Ready(ReadyPacket),
+  RoomSettings(RoomSettingsPacket),
+  LobbyReject(LobbyRejectPacket),
+  LobbyState(LobbyStatePacket),
+  MatchStart(MatchStartPacket),
+  ChatSend(ChatSendPacket),
+  ChatBroadcast(ChatBroadcastPacket),
+  ChatReject(ChatRejectPacket),
+  Ping(PingPacket),
+  Pong(PongPacket),
+  Disconnect(DisconnectPacket),
+  PlayerLeft(PlayerLeftPacket),
+  Input(InputPacket),
+  Snapshot(SnapshotPacket),
+  DeltaSnapshot(DeltaSnapshotPacket),
+  SnapshotAck(SnapshotAckPacket),
+}
+
+impl AnyPacket {
+  /// Decode a frame produced by a `FrameDecoder`.
+  pub fn from_frame(frame: &Frame) -> Result<AnyPacket, DeserialiseError> {
+    AnyPacket::decode(frame.tag, &frame.body)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use net::{FrameEncoder, FrameDecoder};
+  use super::*;
+
+  #[test]
+  fn frames_are_dispatched_by_tag() {
+    let mut decoder = FrameDecoder::default();
+    let encoder = FrameEncoder::message();
+    decoder.push(&encoder.encode(&PingPacket { seq: 5 }).unwrap());
+    decoder.push(&encoder.encode(&PongPacket { seq: 6 }).unwrap());
+    decoder.push(&encoder.encode(&RegPacket::new("alice")).unwrap());
+
+    let mut next = || AnyPacket::from_frame(&decoder.next_frame().unwrap().unwrap()).unwrap();
+    assert!(matches!(next(), AnyPacket::Ping(PingPacket { seq: 5 })));
+    assert!(matches!(next(), AnyPacket::Pong(PongPacket { seq: 6 })));
+    match next() {
+      AnyPacket::Reg(reg) => assert_eq!(reg.name, "alice"),
+      other => panic!("decoded as {:?}", String::from_utf8_lossy(&other.tag())),
+    }
+  }
+
+  #[test]
+  fn tag_matches_the_packet() {
+    let packet = AnyPacket::decode(PingPacket::TAG, &[1, 0, 0, 0]).unwrap();
+    assert_eq!(packet.tag(), TAG_PING);
+  }
+
+  #[test]
+  fn unknown_tag_is_an_error() {
+    assert_eq!(AnyPacket::decode(*b"zzz", &[]).err(), Some(DeserialiseError::UnknownTag(*b"zzz")));
+  }
+
+  #[test]
+  fn body_errors_come_from_the_packet() {
+    assert!(matches!(AnyPacket::decode(TAG_PING, &[1, 0]),
+                     Err(DeserialiseError::Truncated { .. })));
+  }
+}
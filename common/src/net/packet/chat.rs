@@ -0,0 +1,71 @@
+//! Text chat between players. Clients send a `ChatSendPacket`, and the server
+//! checks it and relays it as a `ChatBroadcastPacket` to everyone in scope.
+
+use net::{Packet, Wire, WireReader, DeserialiseError};
+
+/// The longest chat message, in characters.
+pub const MAX_CHAT_LEN : usize = 200;
+
+/// Who a chat message is sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatScope {
+  /// Everyone in the sender's room, in the lobby or in game.
+  Room,
+  /// Everyone on the sender's team in their room.
+  Team,
+  /// A single client, by ID.
+  Whisper(u32),
+}
+
+impl Wire for ChatScope {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    match *self {
+      ChatScope::Room => 0u8.encode(buf),
+      ChatScope::Team => 1u8.encode(buf),
+      ChatScope::Whisper(id) => {
+        2u8.encode(buf);
+        id.encode(buf);
+      }
+    }
+  }
+
+  fn decode(r: &mut WireReader) -> Result<ChatScope, DeserialiseError> {
+    let offset = r.offset();
+    match u8::decode(r)? {
+      0 => Ok(ChatScope::Room),
+      1 => Ok(ChatScope::Team),
+      2 => Ok(ChatScope::Whisper(u32::decode(r)?)),
+      _ => Err(DeserialiseError::InvalidValue { offset, reason: "unknown chat scope" }),
+    }
+  }
+}
+
+/// Sent by a client to say something.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "chs")]
+pub struct ChatSendPacket {
+  pub scope: ChatScope,
+  /// At most `MAX_CHAT_LEN` characters.
+  pub text: String,
+}
+
+/// Sent by the server to every client a chat message was sent to. Whispers
+/// are also echoed back to the sender.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "chb")]
+pub struct ChatBroadcastPacket {
+  pub sender_id: u32,
+  pub sender_name: String,
+  pub scope: ChatScope,
+  /// The message, after the server's filtering.
+  pub text: String,
+}
+
+/// Sent by the server when a chat message wasn't sent, such as when the
+/// client is sending too quickly.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "chr")]
+pub struct ChatRejectPacket {
+  /// Why the message wasn't sent, for showing to the user.
+  pub reason: String,
+}
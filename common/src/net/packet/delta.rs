@@ -0,0 +1,318 @@
+//! Delta compressed snapshots. Rather than sending the whole world every comm
+//! tick, the server diffs the current snapshot against the last one the client
+//! acknowledged, and only sends the entities and fields which changed. If
+//! there is no usable baseline, every entity is sent in full.
+
+use std::collections::{HashMap, HashSet};
+use net::{Packet, Wire, WireReader, BitWriter, BitReader, DeserialiseError, FrameEncoder,
+          SerialiseError, SnapshotPacket, EntityState, InputAck, POSITION_QUANTIZER,
+          VELOCITY_QUANTIZER, COLOR_QUANTIZER};
+
+/// The number of sent snapshots the server keeps per client to use as
+/// baselines, and the number of received snapshots the client keeps to decode
+/// against. At the 20Hz comm tickrate this is 1.6 seconds. Baselines older
+/// than this fall back to a full snapshot.
+pub const SNAPSHOT_HISTORY_LEN : usize = 32;
+
+const DIRTY_POS : u32 = 1 << 0;
+const DIRTY_SIZE : u32 = 1 << 1;
+const DIRTY_VEL : u32 = 1 << 2;
+const DIRTY_COLOR : u32 = 1 << 3;
+const DIRTY_GROUNDED : u32 = 1 << 4;
+const DIRTY_BITS : u32 = 5;
+const DIRTY_ALL : u32 = DIRTY_POS | DIRTY_SIZE | DIRTY_VEL | DIRTY_COLOR | DIRTY_GROUNDED;
+
+/// The changed fields of an entity. Fields which are `None` are the same as in
+/// the baseline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntityDelta {
+  pub id: u32,
+  /// X, Y of the AABB
+  pub pos: Option<[f32; 2]>,
+  /// W, H of the AABB
+  pub size: Option<[f32; 2]>,
+  pub vel: Option<[f32; 2]>,
+  pub color: Option<[f32; 4]>,
+  pub grounded: Option<bool>,
+}
+
+/// Compare floats bitwise, so changes such as 0.0 to -0.0 are still sent.
+fn same(a: &[f32], b: &[f32]) -> bool {
+  a.iter().zip(b).all(|(x, y)| x.to_bits() == y.to_bits())
+}
+
+impl EntityDelta {
+  /// Diff an entity against its state in the baseline.
+  /// # Returns
+  /// `None` if the entity is unchanged.
+  pub fn diff(base: Option<&EntityState>, current: &EntityState) -> Option<EntityDelta> {
+    let dirty = |get: fn(&EntityState) -> &[f32]| base.is_none_or(|b| !same(get(b), get(current)));
+    let delta = EntityDelta {
+      id: current.id,
+      pos: dirty(|e| &e.aabb[..2]).then_some([current.aabb[0], current.aabb[1]]),
+      size: dirty(|e| &e.aabb[2..]).then_some([current.aabb[2], current.aabb[3]]),
+      vel: dirty(|e| &e.vel).then_some(current.vel),
+      color: dirty(|e| &e.color).then_some(current.color),
+      grounded: base.is_none_or(|b| b.grounded != current.grounded).then_some(current.grounded),
+    };
+    if delta.dirty_mask() == 0 { None } else { Some(delta) }
+  }
+
+  /// Apply this delta to an entity's state in the baseline. Entities which
+  /// weren't in the baseline must have every field present.
+  pub fn apply(&self, base: Option<&EntityState>) -> Result<EntityState, DeserialiseError> {
+    let base = match base {
+      Some(base) => *base,
+      None if self.dirty_mask() == DIRTY_ALL => EntityState {
+        id: self.id, aabb: [0.0; 4], vel: [0.0; 2], color: [0.0; 4], grounded: false,
+      },
+      None => return Err(DeserialiseError::InvalidDelta {
+        reason: "new entity is missing fields" }),
+    };
+    let pos = self.pos.unwrap_or([base.aabb[0], base.aabb[1]]);
+    let size = self.size.unwrap_or([base.aabb[2], base.aabb[3]]);
+    Ok(EntityState {
+      id: self.id,
+      aabb: [pos[0], pos[1], size[0], size[1]],
+      vel: self.vel.unwrap_or(base.vel),
+      color: self.color.unwrap_or(base.color),
+      grounded: self.grounded.unwrap_or(base.grounded),
+    })
+  }
+
+  fn dirty_mask(&self) -> u32 {
+    let mut mask = 0;
+    if self.pos.is_some() { mask |= DIRTY_POS; }
+    if self.size.is_some() { mask |= DIRTY_SIZE; }
+    if self.vel.is_some() { mask |= DIRTY_VEL; }
+    if self.color.is_some() { mask |= DIRTY_COLOR; }
+    if self.grounded.is_some() { mask |= DIRTY_GROUNDED; }
+    mask
+  }
+}
+
+impl Wire for EntityDelta {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    self.id.encode(buf);
+    let mut w = BitWriter::new(buf);
+    w.write_bits(self.dirty_mask(), DIRTY_BITS);
+    // The flag fits in the same byte as the mask
+    if let Some(grounded) = self.grounded { w.write_bool(grounded); }
+    w.finish();
+    if let Some(ref pos) = self.pos { POSITION_QUANTIZER.encode(pos, buf); }
+    if let Some(ref size) = self.size { POSITION_QUANTIZER.encode(size, buf); }
+    if let Some(ref vel) = self.vel { VELOCITY_QUANTIZER.encode(vel, buf); }
+    if let Some(ref color) = self.color { COLOR_QUANTIZER.encode(color, buf); }
+  }
+
+  fn decode(r: &mut WireReader) -> Result<EntityDelta, DeserialiseError> {
+    let id = u32::decode(r)?;
+    let (mask, grounded) = {
+      let mut bits = BitReader::new(r);
+      let mask = bits.read_bits(DIRTY_BITS)?;
+      let grounded = if mask & DIRTY_GROUNDED != 0 { Some(bits.read_bool()?) } else { None };
+      (mask, grounded)
+    };
+    Ok(EntityDelta {
+      id,
+      pos: if mask & DIRTY_POS != 0 { Some(POSITION_QUANTIZER.decode(r)?) } else { None },
+      size: if mask & DIRTY_SIZE != 0 { Some(POSITION_QUANTIZER.decode(r)?) } else { None },
+      vel: if mask & DIRTY_VEL != 0 { Some(VELOCITY_QUANTIZER.decode(r)?) } else { None },
+      color: if mask & DIRTY_COLOR != 0 { Some(COLOR_QUANTIZER.decode(r)?) } else { None },
+      grounded,
+    })
+  }
+}
+
+/// A snapshot encoded as the difference from an earlier snapshot.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "dsn")]
+pub struct DeltaSnapshotPacket {
+  /// The server tick this snapshot was taken on.
+  pub tick: u32,
+  /// The tick of the snapshot this is a delta against, or `None` if this is a
+  /// full snapshot.
+  pub baseline: Option<u32>,
+  /// The last processed input tick for each client. Always sent in full.
+  pub acks: Vec<InputAck>,
+  /// Entities which are new or have changed since the baseline.
+  pub changed: Vec<EntityDelta>,
+  /// Ids of entities in the baseline which no longer exist.
+  pub removed: Vec<u32>,
+}
+
+impl DeltaSnapshotPacket {
+  /// Diff a snapshot against a baseline.
+  /// # Params
+  /// * `baseline` - The last snapshot acknowledged by the client, or `None`
+  ///   to send a full snapshot.
+  /// * `current` - The snapshot to send.
+  pub fn diff(baseline: Option<&SnapshotPacket>, current: &SnapshotPacket) -> DeltaSnapshotPacket {
+    let base_entities : HashMap<u32, &EntityState> = baseline
+      .map(|b| b.entities.iter().map(|e| (e.id, e)).collect())
+      .unwrap_or_default();
+    let current_ids : HashSet<u32> = current.entities.iter().map(|e| e.id).collect();
+
+    DeltaSnapshotPacket {
+      tick: current.tick,
+      baseline: baseline.map(|b| b.tick),
+      acks: current.acks.clone(),
+      changed: current.entities.iter()
+        .filter_map(|e| EntityDelta::diff(base_entities.get(&e.id).cloned(), e))
+        .collect(),
+      removed: baseline.map(|b| b.entities.iter()
+                              .filter(|e| !current_ids.contains(&e.id))
+                              .map(|e| e.id)
+                              .collect())
+        .unwrap_or_default(),
+    }
+  }
+
+  /// Rebuild the full snapshot from this delta. Entities carried over from
+  /// the baseline keep their order, and new entities are appended.
+  /// # Params
+  /// * `baseline` - The snapshot with the tick given in `self.baseline`.
+  ///   Ignored if this is a full snapshot.
+  pub fn apply(&self, baseline: Option<&SnapshotPacket>) -> Result<SnapshotPacket, DeserialiseError> {
+    let base_entities = match (self.baseline, baseline) {
+      (None, _) => &[][..],
+      (Some(tick), Some(b)) if b.tick == tick => &b.entities[..],
+      (Some(tick), _) => return Err(DeserialiseError::UnknownBaseline { tick }),
+    };
+
+    let removed : HashSet<u32> = self.removed.iter().cloned().collect();
+    let mut entities : Vec<EntityState> = base_entities.iter()
+      .filter(|e| !removed.contains(&e.id))
+      .cloned()
+      .collect();
+    let mut index : HashMap<u32, usize> = entities.iter().enumerate()
+      .map(|(i, e)| (e.id, i))
+      .collect();
+
+    for delta in &self.changed {
+      match index.get(&delta.id).cloned() {
+        Some(i) => entities[i] = delta.apply(Some(&entities[i]))?,
+        None => {
+          index.insert(delta.id, entities.len());
+          entities.push(delta.apply(None)?);
+        }
+      }
+    }
+
+    Ok(SnapshotPacket { tick: self.tick, acks: self.acks.clone(), entities })
+  }
+
+  /// Serialise and frame this snapshot, checking it fits in a single UDP
+  /// datagram.
+  /// # Returns
+  /// `SerialiseError::FrameTooLarge` if the snapshot is too large to be sent
+  /// in one datagram.
+  pub fn encode_datagram(&self) -> Result<Vec<u8>, SerialiseError> {
+    FrameEncoder::datagram().encode(self)
+  }
+}
+
+/// Sent by the client to acknowledge it has received a snapshot, so the server
+/// can use it as the baseline for future deltas.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "sak")]
+pub struct SnapshotAckPacket {
+  pub tick: u32,
+}
+
+#[cfg(test)]
+mod tests {
+  use net::MAX_DATAGRAM_SIZE;
+  use super::*;
+
+  fn entity(id: u32, x: f32) -> EntityState {
+    EntityState {
+      id, aabb: [x, 10.0, 32.0, 32.0], vel: [1.0, 0.0], color: [1.0, 0.0, 0.0, 1.0], grounded: false,
+    }
+  }
+
+  fn snapshot(tick: u32, entities: Vec<EntityState>) -> SnapshotPacket {
+    SnapshotPacket { tick, acks: vec![InputAck { client_id: 65536, tick: tick - 1 }], entities }
+  }
+
+  #[test]
+  fn diff_then_apply_round_trips() {
+    let base = snapshot(10, vec![entity(0, 0.0), entity(1, 50.0), entity(2, 100.0)]);
+    let mut moved = entity(1, 60.0);
+    moved.vel = [2.0, 0.0];
+    moved.grounded = true;
+    let current = snapshot(13, vec![entity(0, 0.0), moved, entity(3, 200.0)]);
+
+    let delta = DeltaSnapshotPacket::diff(Some(&base), &current);
+    assert_eq!(delta.baseline, Some(10));
+    assert_eq!(delta.removed, vec![2]);
+    // Only the moved entity's changed fields, and the new entity, are sent
+    assert_eq!(delta.changed.len(), 2);
+    assert_eq!(delta.changed[0], EntityDelta {
+      id: 1, pos: Some([60.0, 10.0]), size: None, vel: Some([2.0, 0.0]), color: None,
+      grounded: Some(true),
+    });
+    assert_eq!(delta.apply(Some(&base)).unwrap(), current);
+  }
+
+  #[test]
+  fn full_snapshot_needs_no_baseline() {
+    let current = snapshot(5, vec![entity(7, 1.0), entity(8, 2.0)]);
+    let delta = DeltaSnapshotPacket::diff(None, &current);
+    assert_eq!(delta.baseline, None);
+    assert_eq!(delta.apply(None).unwrap(), current);
+  }
+
+  #[test]
+  fn unchanged_world_sends_no_entities() {
+    let base = snapshot(1, vec![entity(0, 0.0), entity(1, 5.0)]);
+    let current = SnapshotPacket { tick: 4, ..base.clone() };
+    let delta = DeltaSnapshotPacket::diff(Some(&base), &current);
+    assert!(delta.changed.is_empty() && delta.removed.is_empty());
+    assert_eq!(delta.apply(Some(&base)).unwrap().entities, base.entities);
+  }
+
+  #[test]
+  fn missing_baseline_is_an_error() {
+    let base = snapshot(10, vec![entity(0, 0.0)]);
+    let delta = DeltaSnapshotPacket::diff(Some(&base), &snapshot(13, vec![entity(0, 1.0)]));
+    assert_eq!(delta.apply(None), Err(DeserialiseError::UnknownBaseline { tick: 10 }));
+    let other = snapshot(7, vec![entity(0, 0.0)]);
+    assert_eq!(delta.apply(Some(&other)), Err(DeserialiseError::UnknownBaseline { tick: 10 }));
+  }
+
+  #[test]
+  fn new_entity_must_be_complete() {
+    let delta = EntityDelta {
+      id: 4, pos: Some([1.0, 2.0]), size: None, vel: None, color: None, grounded: None,
+    };
+    assert!(matches!(delta.apply(None), Err(DeserialiseError::InvalidDelta { .. })));
+  }
+
+  #[test]
+  fn oversized_snapshot_doesnt_fit_a_datagram() {
+    let full = |count| {
+      DeltaSnapshotPacket::diff(None, &snapshot(1, (0..count).map(|id| entity(id, 0.0)).collect()))
+    };
+    assert!(full(10).encode_datagram().unwrap().len() <= MAX_DATAGRAM_SIZE);
+    let large = full(100);
+    assert!(matches!(large.encode_datagram(), Err(SerialiseError::FrameTooLarge { .. })));
+  }
+
+  #[test]
+  fn only_dirty_fields_are_encoded() {
+    let delta = EntityDelta {
+      id: 1, pos: None, size: None, vel: Some([3.0, -4.0]), color: None, grounded: Some(true),
+    };
+    let mut buf = Vec::new();
+    delta.encode(&mut buf);
+    // ID, the dirty mask and grounded flag, then two 22 bit velocities
+    assert_eq!(buf.len(), 4 + 1 + 6);
+    let decoded = EntityDelta::decode(&mut WireReader::new(&buf)).unwrap();
+    assert_eq!((decoded.id, decoded.pos, decoded.size, decoded.color), (1, None, None, None));
+    assert_eq!(decoded.grounded, Some(true));
+    let vel = decoded.vel.unwrap();
+    assert!((vel[0] - 3.0).abs() <= VELOCITY_QUANTIZER.max_error());
+    assert!((vel[1] + 4.0).abs() <= VELOCITY_QUANTIZER.max_error());
+  }
+}
@@ -0,0 +1,24 @@
+//! Packets for leaving the server. Either side sends a `DisconnectPacket`
+//! over TCP before closing the connection, and the other players in the
+//! room are told with a `PlayerLeftPacket`.
+
+use net::Packet;
+
+/// Sent just before closing the connection.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "dis")]
+pub struct DisconnectPacket {
+  /// Why the connection is being closed, for showing to the user.
+  pub reason: String,
+}
+
+/// Sent by the server to the players in a room when one of them leaves the
+/// server.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "plf")]
+pub struct PlayerLeftPacket {
+  pub client_id: u32,
+  pub name: String,
+  /// Why the player left.
+  pub reason: String,
+}
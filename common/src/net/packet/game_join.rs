@@ -1,25 +1,88 @@
-//! A packet for joining a game. Currently no arguments are needed - there
-//! is only 1 game running on the server, and the client will join that
-//! game when sending the game_join packet to the server.
-
-use net::{Packet, DeserialiseError, TAG_GAME_JOIN};
-
-/// A packet for registration.
-pub struct GameJoinPacket;
-
-impl Packet for GameJoinPacket {
-  fn serialise(&self) -> Vec<u8> {
-    use std::mem::transmute;
-    let mut ret = Vec::with_capacity(7);
-    unsafe { ret.extend_from_slice(&transmute::<u32, [u8; 4]>(0)[..]) };
-    ret.extend_from_slice(TAG_GAME_JOIN.as_bytes());
-    return ret;
+//! Packets for finding and joining a game. The server hosts many rooms at
+//! once, each running its own game. A client can ask for the list of rooms
+//! and join one by ID, or quick match into any room with space.
+
+use net::{Packet, Wire, WireReader, DeserialiseError};
+
+/// The state of a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomState {
+  /// Players are gathering, and the match hasn't started yet.
+  Lobby,
+  /// The match is being played.
+  InGame,
+}
+
+impl Wire for RoomState {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    let id : u8 = match *self {
+      RoomState::Lobby => 0,
+      RoomState::InGame => 1,
+    };
+    id.encode(buf);
   }
 
-  /// Deserialise this packet, from bytes stripped of the length and tag (first
-  /// 7 bytes). 
-  fn deserialise(_: &[u8]) -> Result<GameJoinPacket, DeserialiseError> {
-    Ok(GameJoinPacket)
+  fn decode(r: &mut WireReader) -> Result<RoomState, DeserialiseError> {
+    let offset = r.offset();
+    match u8::decode(r)? {
+      0 => Ok(RoomState::Lobby),
+      1 => Ok(RoomState::InGame),
+      _ => Err(DeserialiseError::InvalidValue { offset, reason: "unknown room state" }),
+    }
   }
 }
 
+/// A summary of a room, as listed in a `GameListPacket`.
+#[derive(Wire, Debug, Clone, Copy, PartialEq)]
+pub struct RoomInfo {
+  pub id: u32,
+  pub players: u32,
+  pub max_players: u32,
+  pub state: RoomState,
+}
+
+/// A packet for joining a game. Must be sent after registering.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "gmj")]
+pub struct GameJoinPacket {
+  /// The room to join, or `None` to quick match into any room with space.
+  pub room_id: Option<u32>,
+}
+
+impl GameJoinPacket {
+  pub fn room(room_id: u32) -> GameJoinPacket {
+    GameJoinPacket { room_id: Some(room_id) }
+  }
+
+  pub fn quick_match() -> GameJoinPacket {
+    GameJoinPacket { room_id: None }
+  }
+}
+
+/// Sent by the server when the client has joined a room.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "gja")]
+pub struct GameJoinAckPacket {
+  pub room_id: u32,
+}
+
+/// Sent by the server when the client couldn't join a room, for example
+/// because it's full.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "gjr")]
+pub struct GameJoinRejectPacket {
+  /// Why the client couldn't join, for showing to the user.
+  pub reason: String,
+}
+
+/// Asks the server for a `GameListPacket`.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "glr")]
+pub struct GameListRequestPacket;
+
+/// The rooms currently on the server.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "gls")]
+pub struct GameListPacket {
+  pub rooms: Vec<RoomInfo>,
+}
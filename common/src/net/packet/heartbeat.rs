@@ -0,0 +1,20 @@
+//! Packets for keeping a connection alive. The server pings each client over
+//! UDP at a regular interval, and the client replies with a pong. A client
+//! the server hasn't heard from in too long is dropped.
+
+use net::Packet;
+
+/// Sent by the server to check the client is still there.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "png")]
+pub struct PingPacket {
+  /// Incremented with each ping, and echoed in the pong.
+  pub seq: u32,
+}
+
+/// Sent by the client in reply to a `PingPacket`.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "pog")]
+pub struct PongPacket {
+  pub seq: u32,
+}
@@ -0,0 +1,45 @@
+//! Packets for negotiating the protocol version. See `net::version`. The
+//! layout of these packets must stay the same across every version.
+
+use net::{Packet, PROTOCOL_VERSION, SUPPORTED_FEATURES};
+
+/// The first packet a client sends over TCP.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "hlo")]
+pub struct HelloPacket {
+  pub version: u16,
+  /// Bitmask of supported features, see the `FEATURE_*` constants.
+  pub features: u32,
+}
+
+impl HelloPacket {
+  /// Create a hello for this build's protocol.
+  pub fn new() -> HelloPacket {
+    HelloPacket { version: PROTOCOL_VERSION, features: SUPPORTED_FEATURES }
+  }
+}
+
+impl Default for HelloPacket {
+  fn default() -> HelloPacket {
+    HelloPacket::new()
+  }
+}
+
+/// Sent by the server when it accepts a client's protocol.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "hak")]
+pub struct HelloAckPacket {
+  /// The server's protocol version.
+  pub version: u16,
+  /// The features both the client and server support.
+  pub features: u32,
+}
+
+/// Sent by the server when the client's protocol is incompatible, just before
+/// it closes the connection.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "hrj")]
+pub struct HelloRejectPacket {
+  /// Why the client was rejected, for showing to the user.
+  pub reason: String,
+}
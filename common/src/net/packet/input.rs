@@ -0,0 +1,115 @@
+//! A packet containing the client's whole input state at a given tick. The
+//! state is sent whenever an input changes, rather than sending a packet per
+//! key press, so a dropped packet is corrected by the next one.
+
+use std::f32::consts::PI;
+use net::{Packet, Tag, Wire, WireReader, BitWriter, BitReader, DeserialiseError, Quantizer,
+          Quantize, decode_body};
+
+/// Button bit for moving left.
+pub const INPUT_LEFT : u8 = 1 << 0;
+/// Button bit for moving right.
+pub const INPUT_RIGHT : u8 = 1 << 1;
+/// Button bit for jumping.
+pub const INPUT_JUMP : u8 = 1 << 2;
+/// Button bit for shooting.
+pub const INPUT_SHOOT : u8 = 1 << 3;
+/// The number of bits used to send the button state.
+pub const INPUT_BUTTON_BITS : u32 = 4;
+
+/// Quantizer for analog axes.
+pub const AXIS_QUANTIZER : Quantizer = Quantizer::new(-1.0, 1.0, 10);
+/// Quantizer for the aim angle, to within about a tenth of a degree.
+pub const AIM_QUANTIZER : Quantizer = Quantizer::new(-PI, PI, 12);
+
+/// A packet holding the discrete and analog input state of a client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputPacket {
+  /// The game tick the input was made on.
+  pub tick: u32,
+  /// Bitmask of held buttons, see the `INPUT_*` constants.
+  pub buttons: u8,
+  /// Analog movement axes, X then Y, each from -1 to 1.
+  pub axes: Option<[f32; 2]>,
+  /// Aim angle in radians, from -PI to PI.
+  pub aim: Option<f32>,
+}
+
+impl InputPacket {
+  /// Create an input packet with only button state.
+  pub fn new(tick: u32, buttons: u8) -> InputPacket {
+    InputPacket { tick, buttons, axes: None, aim: None }
+  }
+
+  /// Check whether a button (one of the `INPUT_*` constants) is held.
+  pub fn is_held(&self, button: u8) -> bool {
+    self.buttons & button != 0
+  }
+}
+
+impl Wire for InputPacket {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    self.tick.encode(buf);
+    let mut w = BitWriter::new(buf);
+    w.write_bits(self.buttons as u32, INPUT_BUTTON_BITS);
+    w.write_bool(self.axes.is_some());
+    w.write_bool(self.aim.is_some());
+    if let Some(ref axes) = self.axes {
+      axes.write_quantized(&AXIS_QUANTIZER, &mut w);
+    }
+    if let Some(ref aim) = self.aim {
+      aim.write_quantized(&AIM_QUANTIZER, &mut w);
+    }
+    w.finish();
+  }
+
+  fn decode(r: &mut WireReader) -> Result<InputPacket, DeserialiseError> {
+    let tick = u32::decode(r)?;
+    let mut br = BitReader::new(r);
+    let buttons = br.read_bits(INPUT_BUTTON_BITS)? as u8;
+    let has_axes = br.read_bool()?;
+    let has_aim = br.read_bool()?;
+    let axes = if has_axes { Some(Quantize::read_quantized(&AXIS_QUANTIZER, &mut br)?) } else { None };
+    let aim = if has_aim { Some(Quantize::read_quantized(&AIM_QUANTIZER, &mut br)?) } else { None };
+    Ok(InputPacket { tick, buttons, axes, aim })
+  }
+}
+
+impl Packet for InputPacket {
+  const TAG: Tag = *b"inp";
+
+  fn serialise_body(&self, buf: &mut Vec<u8>) {
+    self.encode(buf);
+  }
+
+  fn deserialise(buf: &[u8]) -> Result<InputPacket, DeserialiseError> {
+    decode_body(buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn buttons_only_fit_in_a_byte_after_the_tick() {
+    let input = InputPacket::new(70000, INPUT_LEFT | INPUT_JUMP);
+    let mut buf = Vec::new();
+    input.serialise_body(&mut buf);
+    assert_eq!(buf.len(), 5);
+    assert_eq!(InputPacket::deserialise(&buf).unwrap(), input);
+  }
+
+  #[test]
+  fn analog_state_round_trips_within_error() {
+    let input = InputPacket { tick: 3, buttons: INPUT_SHOOT, axes: Some([-0.5, 0.25]), aim: Some(1.0) };
+    let mut buf = Vec::new();
+    input.serialise_body(&mut buf);
+    let decoded = InputPacket::deserialise(&buf).unwrap();
+    assert_eq!((decoded.tick, decoded.buttons), (3, INPUT_SHOOT));
+    for (d, v) in decoded.axes.unwrap().iter().zip(&[-0.5, 0.25]) {
+      assert!((d - v).abs() <= AXIS_QUANTIZER.max_error());
+    }
+    assert!((decoded.aim.unwrap() - 1.0).abs() <= AIM_QUANTIZER.max_error());
+  }
+}
@@ -0,0 +1,68 @@
+//! Packets for a room's lobby, where players gather before the match starts.
+//! Players toggle whether they're ready, and the room's host can change its
+//! settings. The match starts once every player is ready. Whenever anything
+//! changes, the server sends every player in the room a `LobbyStatePacket`.
+
+use net::{Packet, Wire};
+
+/// The settings of a room, chosen by its host.
+#[derive(Wire, Debug, Clone, PartialEq)]
+pub struct RoomSettings {
+  /// The name of the map to play on.
+  pub map: String,
+  pub max_players: u32,
+  /// The game tickrate, in ticks per second.
+  pub tickrate: u32,
+}
+
+/// A player in a lobby.
+#[derive(Wire, Debug, Clone, PartialEq)]
+pub struct LobbyPlayer {
+  pub client_id: u32,
+  pub name: String,
+  pub team: u8,
+  pub ready: bool,
+}
+
+/// Sent by a client to mark itself as ready, or not ready, to start.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "rdy")]
+pub struct ReadyPacket {
+  pub ready: bool,
+}
+
+/// Sent by the host of a room to change its settings.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "rst")]
+pub struct RoomSettingsPacket {
+  pub settings: RoomSettings,
+}
+
+/// Sent by the server when a lobby request can't be carried out, such as a
+/// settings change from a player who isn't the host.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "lrj")]
+pub struct LobbyRejectPacket {
+  /// Why the request was rejected, for showing to the user.
+  pub reason: String,
+}
+
+/// The state of a room's lobby, sent to its players whenever it changes.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "lby")]
+pub struct LobbyStatePacket {
+  pub room_id: u32,
+  /// The client ID of the room's host.
+  pub host: u32,
+  pub settings: RoomSettings,
+  /// The players in the room, in the order they joined.
+  pub players: Vec<LobbyPlayer>,
+}
+
+/// Sent to every player in a room when its match starts.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "mst")]
+pub struct MatchStartPacket {
+  pub room_id: u32,
+  pub settings: RoomSettings,
+}
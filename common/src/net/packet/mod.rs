@@ -1,44 +1,286 @@
 //! A module for packets shared across the network, plus serialisation /
 //! deserialisation methods.
 
+mod hello;
 mod reg;
 mod game_join;
+mod lobby;
+mod chat;
+mod heartbeat;
+mod disconnect;
+mod udp_hello;
+mod input;
+mod snapshot;
+mod delta;
+mod any;
 
-pub use self::reg::RegPacket;
-pub use self::game_join::GameJoinPacket;
+pub use self::hello::{HelloPacket, HelloAckPacket, HelloRejectPacket};
+pub use self::reg::{RegPacket, RegAckPacket, RegRejectPacket};
+pub use self::game_join::*;
+pub use self::lobby::*;
+pub use self::chat::*;
+pub use self::heartbeat::{PingPacket, PongPacket};
+pub use self::disconnect::{DisconnectPacket, PlayerLeftPacket};
+pub use self::udp_hello::UdpHelloPacket;
+pub use self::input::*;
+pub use self::snapshot::*;
+pub use self::delta::*;
+pub use self::any::AnyPacket;
 
 use std::{fmt, error};
+use std::str::Utf8Error;
 
-/// A class for errors when deserialising bytes into a packet.
-#[derive(Debug, Clone, Copy, Hash, PartialEq, PartialOrd)]
+/// A 3 byte tag identifying the type of a packet on the wire.
+pub type Tag = [u8; 3];
+
+/// A class for errors when deserialising bytes into a packet. Offsets are in
+/// bytes from the start of the frame body.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DeserialiseError {
-  DataBad,
+  /// The data ended before a value could be read in full.
+  Truncated { offset: usize, needed: usize, available: usize },
+  /// A string was not valid UTF-8.
+  InvalidUtf8 { offset: usize, error: Utf8Error },
+  /// A value was read in full, but was not valid for its type - i.e. a bool
+  /// which was neither 0 nor 1.
+  InvalidValue { offset: usize, reason: &'static str },
+  /// A frame's tag did not match any known packet type.
+  UnknownTag(Tag),
+  /// A frame header declared a body larger than the decoder accepts.
+  FrameTooLarge { size: usize, max: usize },
+  /// The packet was decoded, but the body had bytes left over.
+  TrailingBytes { offset: usize, count: usize },
+  /// A delta snapshot was against a baseline which is no longer held.
+  UnknownBaseline { tick: u32 },
+  /// A delta snapshot could not be applied to its baseline.
+  InvalidDelta { reason: &'static str },
 }
 
 impl fmt::Display for DeserialiseError {
   fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-    write!(f, "{:?}", self)
+    match *self {
+      DeserialiseError::Truncated { offset, needed, available } =>
+        write!(f, "data truncated at byte {}: needed {} bytes, {} available",
+               offset, needed, available),
+      DeserialiseError::InvalidUtf8 { offset, .. } =>
+        write!(f, "invalid UTF-8 at byte {}", offset),
+      DeserialiseError::InvalidValue { offset, reason } =>
+        write!(f, "invalid value at byte {}: {}", offset, reason),
+      DeserialiseError::UnknownTag(tag) =>
+        write!(f, "unknown packet tag {:?}", String::from_utf8_lossy(&tag)),
+      DeserialiseError::FrameTooLarge { size, max } =>
+        write!(f, "frame of {} bytes exceeds the maximum of {} bytes", size, max),
+      DeserialiseError::TrailingBytes { offset, count } =>
+        write!(f, "{} unexpected bytes after the end of the packet at byte {}",
+               count, offset),
+      DeserialiseError::UnknownBaseline { tick } =>
+        write!(f, "delta against unknown baseline tick {}", tick),
+      DeserialiseError::InvalidDelta { reason } =>
+        write!(f, "invalid delta: {}", reason),
+    }
   }
 }
 
 impl error::Error for DeserialiseError {
-  fn description(&self) -> &str {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match *self {
+      DeserialiseError::InvalidUtf8 { ref error, .. } => Some(error),
+      _ => None,
+    }
+  }
+}
+
+/// A class for errors when serialising a packet into bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialiseError {
+  /// The serialised body was larger than the encoder's maximum frame size.
+  FrameTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for SerialiseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
     match *self {
-      DeserialiseError::DataBad => r#"The data was of bad format, or was
-        incomplete and impossible to parse in some way - i.e. a string was
-        given with a length, but the data was not long enough for the entire
-        string to be contained."#,
+      SerialiseError::FrameTooLarge { size, max } =>
+        write!(f, "frame of {} bytes exceeds the maximum of {} bytes", size, max),
     }
   }
-  fn cause(&self) -> Option<&error::Error> { None }
 }
 
+impl error::Error for SerialiseError {}
+
 /// A trait for packets to implement, guaranteeing the serialise / deserialise
-/// methods.
+/// methods. Packets only deal with their body - framing is handled by
+/// `FrameEncoder` and `FrameDecoder`. Usually this is derived along with
+/// `Wire`, using `#[derive(Packet)]` and a `#[packet(tag = "abc")]` attribute.
+///
+/// ```
+/// extern crate common;
+/// use common::net::Packet;
+///
+/// #[derive(Packet)]
+/// #[packet(tag = "abc")]
+/// struct Example { value: u32 }
+///
+/// fn main() {
+///   assert_eq!(&Example::TAG, b"abc");
+///   let _ = Example { value: 1 }.value;
+/// }
+/// ```
+///
+/// Tags must be exactly 3 bytes:
+///
+/// ```compile_fail
+/// extern crate common;
+/// use common::net::Packet;
+///
+/// #[derive(Packet)]
+/// #[packet(tag = "abcd")]
+/// struct Example { value: u32 }
+///
+/// fn main() {}
+/// ```
+///
+/// And can't be left out:
+///
+/// ```compile_fail
+/// extern crate common;
+/// use common::net::Packet;
+///
+/// #[derive(Packet)]
+/// struct Example { value: u32 }
+///
+/// fn main() {}
+/// ```
 pub trait Packet {
-  fn serialise(&self) -> Vec<u8>;
+  /// The tag identifying this packet on the wire.
+  const TAG: Tag;
+  /// Serialise the body of this packet, appending it to `buf`.
+  fn serialise_body(&self, buf: &mut Vec<u8>);
+  /// Deserialise this packet from a frame body.
   fn deserialise(buf: &[u8]) -> Result<Self, DeserialiseError> where Self: Sized;
 }
 
-pub const TAG_REGISTER : &'static str = "reg";
-pub const TAG_GAME_JOIN : &'static str = "gmj";
+pub const TAG_HELLO : Tag = <HelloPacket as Packet>::TAG;
+pub const TAG_HELLO_ACK : Tag = <HelloAckPacket as Packet>::TAG;
+pub const TAG_HELLO_REJECT : Tag = <HelloRejectPacket as Packet>::TAG;
+pub const TAG_REGISTER : Tag = <RegPacket as Packet>::TAG;
+pub const TAG_REG_ACK : Tag = <RegAckPacket as Packet>::TAG;
+pub const TAG_REG_REJECT : Tag = <RegRejectPacket as Packet>::TAG;
+pub const TAG_UDP_HELLO : Tag = <UdpHelloPacket as Packet>::TAG;
+pub const TAG_GAME_JOIN : Tag = <GameJoinPacket as Packet>::TAG;
+pub const TAG_GAME_JOIN_ACK : Tag = <GameJoinAckPacket as Packet>::TAG;
+pub const TAG_GAME_JOIN_REJECT : Tag = <GameJoinRejectPacket as Packet>::TAG;
+pub const TAG_GAME_LIST_REQUEST : Tag = <GameListRequestPacket as Packet>::TAG;
+pub const TAG_GAME_LIST : Tag = <GameListPacket as Packet>::TAG;
+pub const TAG_READY : Tag = <ReadyPacket as Packet>::TAG;
+pub const TAG_ROOM_SETTINGS : Tag = <RoomSettingsPacket as Packet>::TAG;
+pub const TAG_LOBBY_REJECT : Tag = <LobbyRejectPacket as Packet>::TAG;
+pub const TAG_LOBBY_STATE : Tag = <LobbyStatePacket as Packet>::TAG;
+pub const TAG_MATCH_START : Tag = <MatchStartPacket as Packet>::TAG;
+pub const TAG_CHAT_SEND : Tag = <ChatSendPacket as Packet>::TAG;
+pub const TAG_CHAT_BROADCAST : Tag = <ChatBroadcastPacket as Packet>::TAG;
+pub const TAG_CHAT_REJECT : Tag = <ChatRejectPacket as Packet>::TAG;
+pub const TAG_PING : Tag = <PingPacket as Packet>::TAG;
+pub const TAG_PONG : Tag = <PongPacket as Packet>::TAG;
+pub const TAG_DISCONNECT : Tag = <DisconnectPacket as Packet>::TAG;
+pub const TAG_PLAYER_LEFT : Tag = <PlayerLeftPacket as Packet>::TAG;
+pub const TAG_INPUT : Tag = <InputPacket as Packet>::TAG;
+pub const TAG_SNAPSHOT : Tag = <SnapshotPacket as Packet>::TAG;
+pub const TAG_DELTA_SNAPSHOT : Tag = <DeltaSnapshotPacket as Packet>::TAG;
+pub const TAG_SNAPSHOT_ACK : Tag = <SnapshotAckPacket as Packet>::TAG;
+
+#[cfg(test)]
+mod tests {
+  use net::{Packet, Wire, WireReader, COLOR_QUANTIZER};
+  use super::*;
+
+  #[derive(Wire, Debug, Clone, PartialEq)]
+  struct Inner {
+    flag: bool,
+    #[packet(quantize = COLOR_QUANTIZER)]
+    color: [f32; 4],
+  }
+
+  #[derive(Packet, Debug, PartialEq)]
+  #[packet(tag = "tnm")]
+  struct NamedPacket {
+    id: u32,
+    name: String,
+    list: Vec<u16>,
+    some: Option<i64>,
+    none: Option<i64>,
+    inner: Inner,
+    inners: Vec<Inner>,
+  }
+
+  #[derive(Packet, Debug, PartialEq)]
+  #[packet(tag = "ttp")]
+  struct TuplePacket(u8, String, Inner);
+
+  #[derive(Packet, Debug, PartialEq)]
+  #[packet(tag = "tun")]
+  struct UnitPacket;
+
+  fn inner() -> Inner {
+    Inner { flag: true, color: [0.0, 1.0, 0.0, 1.0] }
+  }
+
+  fn round_trip<P: Packet>(packet: &P) -> P {
+    let mut buf = Vec::new();
+    packet.serialise_body(&mut buf);
+    P::deserialise(&buf).unwrap()
+  }
+
+  #[test]
+  fn tags_match_the_attribute() {
+    assert_eq!(&NamedPacket::TAG, b"tnm");
+    assert_eq!(&TuplePacket::TAG, b"ttp");
+    assert_eq!(&UnitPacket::TAG, b"tun");
+  }
+
+  #[test]
+  fn named_struct_round_trips() {
+    let packet = NamedPacket {
+      id: 70000,
+      name: "caf\u{e9}".to_owned(),
+      list: vec![1, 2, 65535],
+      some: Some(-5),
+      none: None,
+      inner: inner(),
+      inners: vec![inner(), Inner { flag: false, color: [1.0; 4] }],
+    };
+    assert_eq!(round_trip(&packet), packet);
+  }
+
+  #[test]
+  fn tuple_struct_round_trips() {
+    let packet = TuplePacket(7, String::new(), inner());
+    assert_eq!(round_trip(&packet), packet);
+  }
+
+  #[test]
+  fn unit_struct_has_an_empty_body() {
+    let mut buf = Vec::new();
+    UnitPacket.serialise_body(&mut buf);
+    assert!(buf.is_empty());
+    assert_eq!(round_trip(&UnitPacket), UnitPacket);
+  }
+
+  #[test]
+  fn fields_are_encoded_in_order() {
+    let mut buf = Vec::new();
+    TuplePacket(7, "a".to_owned(), inner()).serialise_body(&mut buf);
+    let mut r = WireReader::new(&buf);
+    assert_eq!(u8::decode(&mut r), Ok(7));
+    assert_eq!(String::decode(&mut r), Ok("a".to_owned()));
+    assert_eq!(Inner::decode(&mut r), Ok(inner()));
+  }
+
+  #[test]
+  fn truncated_body_is_an_error() {
+    let mut buf = Vec::new();
+    TuplePacket(7, "abc".to_owned(), inner()).serialise_body(&mut buf);
+    buf.pop();
+    assert!(matches!(TuplePacket::deserialise(&buf), Err(DeserialiseError::Truncated { .. })));
+  }
+}
@@ -1,6 +1,8 @@
-use net::{Packet, DeserialiseError, TAG_REGISTER};
+use net::Packet;
 
 /// A packet for registration.
+#[derive(Packet)]
+#[packet(tag = "reg")]
 pub struct RegPacket {
   pub name: String,
 }
@@ -10,24 +12,22 @@ impl RegPacket {
   }
 }
 
-impl Packet for RegPacket {
-  fn serialise(&self) -> Vec<u8> {
-    use std::mem::transmute;
-    let payload = &self.name;
-    let payload_len = payload.len() as u32;
-    let mut ret = Vec::with_capacity(payload_len as usize + 7);
-    unsafe { ret.extend_from_slice(&transmute::<u32, [u8; 4]>(payload_len)[..]) };
-    ret.extend_from_slice(TAG_REGISTER.as_bytes());
-    ret.extend_from_slice(&payload.as_bytes());
-    return ret;
-  }
-
-  /// Deserialise this packet, from bytes stripped of the length and tag (first
-  /// 7 bytes). 
-  fn deserialise(buf: &[u8]) -> Result<RegPacket, DeserialiseError> {
-    use std::str::from_utf8;
-    let name = try!(from_utf8(buf).map_err(|_| DeserialiseError::DataBad));
-    Ok(RegPacket::new(name))
-  }
+/// Sent by the server over TCP when it accepts a `RegPacket`. The session
+/// token must be sent back in a `UdpHelloPacket` to bind the client's UDP
+/// address.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "rak")]
+pub struct RegAckPacket {
+  /// The ID the server has assigned this client.
+  pub client_id: u32,
+  pub session_token: u64,
 }
 
+/// Sent by the server over TCP when it rejects a `RegPacket`. The client may
+/// try again with a different name.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "rrj")]
+pub struct RegRejectPacket {
+  /// Why the registration was rejected, for showing to the user.
+  pub reason: String,
+}
@@ -0,0 +1,47 @@
+//! A packet containing the state of the world at a given server tick. The
+//! tick lets the client discard snapshots which arrive out of order, and
+//! the input acks let it reconcile its predictions against the server.
+
+use net::{Packet, Wire, Quantizer};
+
+/// Quantizer for entity positions and sizes, to within 1/256 of a unit.
+pub const POSITION_QUANTIZER : Quantizer = Quantizer::new(-65536.0, 65536.0, 24);
+/// Quantizer for entity velocities, to within 1/512 of a unit per second.
+pub const VELOCITY_QUANTIZER : Quantizer = Quantizer::new(-8192.0, 8192.0, 22);
+/// Quantizer for colour channels.
+pub const COLOR_QUANTIZER : Quantizer = Quantizer::new(0.0, 1.0, 8);
+
+/// The last input tick the server has processed for a client.
+#[derive(Wire, Debug, Clone, Copy, PartialEq)]
+pub struct InputAck {
+  pub client_id: u32,
+  pub tick: u32,
+}
+
+/// The state of a single entity. The fields match the client's `CompAABB`,
+/// `CompBody` and `CompColor` components.
+#[derive(Wire, Debug, Clone, Copy, PartialEq)]
+pub struct EntityState {
+  pub id: u32,
+  /// X, Y, W, H
+  #[packet(quantize = POSITION_QUANTIZER)]
+  pub aabb: [f32; 4],
+  #[packet(quantize = VELOCITY_QUANTIZER)]
+  pub vel: [f32; 2],
+  /// R, G, B, A
+  #[packet(quantize = COLOR_QUANTIZER)]
+  pub color: [f32; 4],
+  /// Whether the entity is standing on something, so can jump.
+  pub grounded: bool,
+}
+
+/// A snapshot of the world, sent to clients at the comm tickrate.
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(tag = "snp")]
+pub struct SnapshotPacket {
+  /// The server tick this snapshot was taken on.
+  pub tick: u32,
+  /// The last processed input tick for each client.
+  pub acks: Vec<InputAck>,
+  pub entities: Vec<EntityState>,
+}
@@ -0,0 +1,19 @@
+//! The first datagram a client sends over UDP. The server doesn't know which
+//! address a client's UDP socket will have (it may be behind a NAT, or bound to
+//! an ephemeral port), so the client proves which client it is with the
+//! session token from its `RegAckPacket`, and the server records the address
+//! the datagram came from.
+//!
+//! Unlike other datagrams, this is sent as a bare frame rather than through a
+//! `Connection`, since the server can't pick a connection until it knows who
+//! sent it. The server echoes it back once the address is bound, and the
+//! client resends it until it sees the echo.
+
+use net::Packet;
+
+/// A packet binding the sender's UDP address to a registered client.
+#[derive(Packet, Debug, Clone, Copy, PartialEq)]
+#[packet(tag = "uhl")]
+pub struct UdpHelloPacket {
+  pub session_token: u64,
+}
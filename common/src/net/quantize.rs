@@ -0,0 +1,158 @@
+//! Lossy encoding of floats into a fixed number of bits. A value is clamped
+//! to a declared range, and the range is split into `2^bits - 1` equal steps,
+//! so the decoded value is within half a step of the original.
+//!
+//! Fields of derived packets opt into this with the
+//! `#[packet(quantize = QUANTIZER)]` attribute, where `QUANTIZER` is any
+//! expression evaluating to a `Quantizer`.
+
+use net::{BitWriter, BitReader, WireReader, DeserialiseError};
+
+/// Describes how to quantize a float - the range of values and the number of
+/// bits to encode it in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantizer {
+  min: f32,
+  max: f32,
+  bits: u32,
+}
+
+impl Quantizer {
+  /// Create a new quantizer.
+  /// # Params
+  /// * `min` - The smallest value which can be encoded.
+  /// * `max` - The largest value which can be encoded. Must be above `min`.
+  /// * `bits` - The number of bits to encode values in, from 1 to 32.
+  pub const fn new(min: f32, max: f32, bits: u32) -> Quantizer {
+    assert!(min < max, "quantizer range must not be empty");
+    assert!(bits >= 1 && bits <= 32, "quantizer bits must be from 1 to 32");
+    Quantizer { min, max, bits }
+  }
+
+  /// Create a quantizer using the fewest bits that keep the error within
+  /// `precision` (i.e. half a step) across the range.
+  pub fn with_precision(min: f32, max: f32, precision: f32) -> Quantizer {
+    let steps = (max as f64 - min as f64) / (2.0 * precision as f64);
+    let bits = (steps + 1.0).log2().ceil().clamp(1.0, 32.0) as u32;
+    Quantizer::new(min, max, bits)
+  }
+
+  pub fn bits(&self) -> u32 {
+    self.bits
+  }
+
+  /// The largest difference between a value in range and its decoded form.
+  pub fn max_error(&self) -> f32 {
+    ((self.max as f64 - self.min as f64) / self.steps() / 2.0) as f32
+  }
+
+  /// Quantize a value, clamping it to the range first.
+  pub fn quantize(&self, value: f32) -> u32 {
+    let value = (value as f64).clamp(self.min as f64, self.max as f64);
+    let t = (value - self.min as f64) / (self.max as f64 - self.min as f64);
+    (t * self.steps()).round() as u32
+  }
+
+  /// Turn a quantized value back into a float.
+  pub fn dequantize(&self, quantized: u32) -> f32 {
+    let t = quantized as f64 / self.steps();
+    (self.min as f64 + t * (self.max as f64 - self.min as f64)) as f32
+  }
+
+  /// Write a value, or every component of an array of values, then pad to
+  /// the next byte.
+  pub fn encode<T: Quantize>(&self, value: &T, buf: &mut Vec<u8>) {
+    let mut w = BitWriter::new(buf);
+    value.write_quantized(self, &mut w);
+    w.finish();
+  }
+
+  /// Read a value written with `encode`.
+  pub fn decode<T: Quantize>(&self, r: &mut WireReader) -> Result<T, DeserialiseError> {
+    T::read_quantized(self, &mut BitReader::new(r))
+  }
+
+  fn steps(&self) -> f64 {
+    ((1u64 << self.bits) - 1) as f64
+  }
+}
+
+/// A trait for types made up of floats which can be quantized.
+pub trait Quantize: Sized {
+  fn write_quantized(&self, q: &Quantizer, w: &mut BitWriter);
+  fn read_quantized(q: &Quantizer, r: &mut BitReader) -> Result<Self, DeserialiseError>;
+}
+
+impl Quantize for f32 {
+  fn write_quantized(&self, q: &Quantizer, w: &mut BitWriter) {
+    w.write_bits(q.quantize(*self), q.bits);
+  }
+  fn read_quantized(q: &Quantizer, r: &mut BitReader) -> Result<f32, DeserialiseError> {
+    Ok(q.dequantize(r.read_bits(q.bits)?))
+  }
+}
+
+impl<const N: usize> Quantize for [f32; N] {
+  fn write_quantized(&self, q: &Quantizer, w: &mut BitWriter) {
+    for value in self { value.write_quantized(q, w); }
+  }
+  fn read_quantized(q: &Quantizer, r: &mut BitReader) -> Result<[f32; N], DeserialiseError> {
+    let mut ret = [0.0; N];
+    for value in &mut ret { *value = f32::read_quantized(q, r)?; }
+    Ok(ret)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Round trip evenly spaced values across the range, checking each decoded
+  /// value is within the error bound.
+  fn assert_round_trip(q: Quantizer) {
+    let (min, max) = (q.min as f64, q.max as f64);
+    for i in 0..=1000 {
+      let value = (min + (max - min) * i as f64 / 1000.0) as f32;
+      let mut buf = Vec::new();
+      q.encode(&value, &mut buf);
+      let decoded : f32 = q.decode(&mut WireReader::new(&buf)).unwrap();
+      assert!((decoded - value).abs() <= q.max_error() * 1.0001,
+              "{} decoded as {} with {:?}", value, decoded, q);
+    }
+  }
+
+  #[test]
+  fn round_trip_within_error_bound() {
+    assert_round_trip(Quantizer::new(-1.0, 1.0, 8));
+    assert_round_trip(Quantizer::new(0.0, 1.0, 1));
+    assert_round_trip(Quantizer::new(-65536.0, 65536.0, 24));
+    assert_round_trip(Quantizer::new(-1000.0, 1000.0, 32));
+  }
+
+  #[test]
+  fn precision_picks_enough_bits() {
+    let q = Quantizer::with_precision(-100.0, 100.0, 0.01);
+    assert_eq!(q.bits(), 14);
+    assert!(q.max_error() <= 0.01);
+    assert_round_trip(q);
+  }
+
+  #[test]
+  fn out_of_range_values_are_clamped() {
+    let q = Quantizer::new(-1.0, 1.0, 8);
+    assert_eq!(q.dequantize(q.quantize(5.0)), 1.0);
+    assert_eq!(q.dequantize(q.quantize(-5.0)), -1.0);
+  }
+
+  #[test]
+  fn arrays_pack_into_shared_bytes() {
+    let q = Quantizer::new(0.0, 1.0, 4);
+    let mut buf = Vec::new();
+    q.encode(&[0.0, 1.0, 0.5], &mut buf);
+    assert_eq!(buf.len(), 2);
+    let decoded : [f32; 3] = q.decode(&mut WireReader::new(&buf)).unwrap();
+    for (d, v) in decoded.iter().zip(&[0.0, 1.0, 0.5]) {
+      assert!((d - v).abs() <= q.max_error() * 1.0001);
+    }
+  }
+}
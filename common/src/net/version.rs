@@ -0,0 +1,44 @@
+//! Protocol versioning. The client opens every connection with a
+//! `HelloPacket` holding its protocol version and the optional features it
+//! supports, and the server replies with a `HelloAckPacket` or a
+//! `HelloRejectPacket` explaining why the client can't connect.
+//!
+//! The version must be bumped whenever the format of any packet changes. The
+//! hello packets themselves must never change, so that any two versions can
+//! at least tell each other they're incompatible.
+
+/// The protocol version spoken by this build.
+pub const PROTOCOL_VERSION : u16 = 1;
+/// The oldest protocol version this build can talk to.
+pub const MIN_PROTOCOL_VERSION : u16 = 1;
+
+/// Snapshots are delta compressed against acknowledged baselines.
+pub const FEATURE_DELTA_SNAPSHOTS : u32 = 1 << 0;
+/// Messages larger than a datagram are fragmented.
+pub const FEATURE_FRAGMENTATION : u32 = 1 << 1;
+
+/// Every feature this build supports.
+pub const SUPPORTED_FEATURES : u32 = FEATURE_DELTA_SNAPSHOTS | FEATURE_FRAGMENTATION;
+/// Features the peer must support to connect at all.
+pub const REQUIRED_FEATURES : u32 = FEATURE_DELTA_SNAPSHOTS | FEATURE_FRAGMENTATION;
+
+/// Check whether a peer speaking the given version with the given features
+/// can talk to this build.
+/// # Returns
+/// The features supported by both sides, or a human-readable reason the peer
+/// is incompatible.
+pub fn negotiate(version: u16, features: u32) -> Result<u32, String> {
+  if version < MIN_PROTOCOL_VERSION {
+    return Err(format!("protocol version {} is too old, version {} or newer is required - please update",
+                       version, MIN_PROTOCOL_VERSION));
+  }
+  if version > PROTOCOL_VERSION {
+    return Err(format!("protocol version {} is newer than the server's version {}",
+                       version, PROTOCOL_VERSION));
+  }
+  let missing = REQUIRED_FEATURES & !features;
+  if missing != 0 {
+    return Err(format!("missing required protocol features {:#x}", missing));
+  }
+  Ok(features & SUPPORTED_FEATURES)
+}
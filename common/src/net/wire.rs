@@ -0,0 +1,158 @@
+//! Encoding of individual fields within a packet body. All integers and
+//! floats are little-endian, bools are a single byte, and strings and vectors
+//! are prefixed with their length as a `u32`. Fixed size arrays have no
+//! length prefix, and options are a bool followed by the value if present.
+//!
+//! Packets are normally made of `Wire` fields by deriving `Packet`, which
+//! implements this trait for the packet as well.
+
+use std::convert::TryFrom;
+use net::DeserialiseError;
+
+/// A trait for types which can be written to and read from a packet body.
+pub trait Wire: Sized {
+  /// Append the encoded form of this value to `buf`.
+  fn encode(&self, buf: &mut Vec<u8>);
+  /// Read a value from the reader, advancing it past the value's bytes.
+  fn decode(r: &mut WireReader) -> Result<Self, DeserialiseError>;
+}
+
+/// A cursor over a packet body, used when decoding `Wire` values.
+#[derive(Debug, Clone)]
+pub struct WireReader<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> WireReader<'a> {
+  pub fn new(buf: &'a [u8]) -> WireReader<'a> {
+    WireReader { buf, pos: 0 }
+  }
+
+  /// The offset of the next byte to be read, from the start of the body.
+  pub fn offset(&self) -> usize {
+    self.pos
+  }
+
+  /// The number of bytes not yet read.
+  pub fn remaining(&self) -> usize {
+    self.buf.len() - self.pos
+  }
+
+  /// Read the next `n` bytes.
+  pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], DeserialiseError> {
+    if self.remaining() < n {
+      return Err(DeserialiseError::Truncated {
+        offset: self.pos, needed: n, available: self.remaining() });
+    }
+    let bytes = &self.buf[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(bytes)
+  }
+
+  /// Check that every byte has been read.
+  pub fn finish(&self) -> Result<(), DeserialiseError> {
+    if self.remaining() != 0 {
+      return Err(DeserialiseError::TrailingBytes { offset: self.pos, count: self.remaining() });
+    }
+    Ok(())
+  }
+}
+
+/// Decode a whole packet body as a `Wire` value, failing if any bytes are left
+/// over.
+pub fn decode_body<T: Wire>(buf: &[u8]) -> Result<T, DeserialiseError> {
+  let mut r = WireReader::new(buf);
+  let value = T::decode(&mut r)?;
+  r.finish()?;
+  Ok(value)
+}
+
+macro_rules! impl_wire_num {
+  ($($t:ty),*) => {
+    $(
+      impl Wire for $t {
+        fn encode(&self, buf: &mut Vec<u8>) {
+          buf.extend_from_slice(&self.to_le_bytes());
+        }
+        fn decode(r: &mut WireReader) -> Result<$t, DeserialiseError> {
+          const SIZE : usize = ::std::mem::size_of::<$t>();
+          let mut bytes = [0; SIZE];
+          bytes.copy_from_slice(r.read_bytes(SIZE)?);
+          Ok(<$t>::from_le_bytes(bytes))
+        }
+      }
+    )*
+  }
+}
+
+impl_wire_num!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Wire for bool {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    buf.push(*self as u8);
+  }
+  fn decode(r: &mut WireReader) -> Result<bool, DeserialiseError> {
+    let offset = r.offset();
+    match u8::decode(r)? {
+      0 => Ok(false),
+      1 => Ok(true),
+      _ => Err(DeserialiseError::InvalidValue { offset, reason: "bool must be 0 or 1" }),
+    }
+  }
+}
+
+impl Wire for String {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    (self.len() as u32).encode(buf);
+    buf.extend_from_slice(self.as_bytes());
+  }
+  fn decode(r: &mut WireReader) -> Result<String, DeserialiseError> {
+    let len = u32::decode(r)? as usize;
+    let offset = r.offset();
+    let bytes = r.read_bytes(len)?;
+    let s = ::std::str::from_utf8(bytes).map_err(|error| {
+      DeserialiseError::InvalidUtf8 { offset: offset + error.valid_up_to(), error }
+    })?;
+    Ok(s.to_owned())
+  }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    (self.len() as u32).encode(buf);
+    for item in self { item.encode(buf); }
+  }
+  fn decode(r: &mut WireReader) -> Result<Vec<T>, DeserialiseError> {
+    let len = u32::decode(r)? as usize;
+    // Don't trust the length when allocating, a malicious peer could claim
+    // billions of items.
+    let mut ret = Vec::with_capacity(len.min(r.remaining()));
+    for _ in 0..len { ret.push(T::decode(r)?); }
+    Ok(ret)
+  }
+}
+
+impl<T: Wire, const N: usize> Wire for [T; N] {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    for item in self { item.encode(buf); }
+  }
+  fn decode(r: &mut WireReader) -> Result<[T; N], DeserialiseError> {
+    let mut items = Vec::with_capacity(N);
+    for _ in 0..N { items.push(T::decode(r)?); }
+    match <[T; N]>::try_from(items) {
+      Ok(array) => Ok(array),
+      Err(_) => unreachable!("exactly N items were decoded"),
+    }
+  }
+}
+
+impl<T: Wire> Wire for Option<T> {
+  fn encode(&self, buf: &mut Vec<u8>) {
+    self.is_some().encode(buf);
+    if let Some(ref value) = *self { value.encode(buf); }
+  }
+  fn decode(r: &mut WireReader) -> Result<Option<T>, DeserialiseError> {
+    if bool::decode(r)? { Ok(Some(T::decode(r)?)) } else { Ok(None) }
+  }
+}
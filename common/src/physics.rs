@@ -0,0 +1,324 @@
+//! The physics simulation shared by the server and client prediction. Both
+//! must step the world in exactly the same way, or predictions will drift
+//! from the server, so the step only uses basic float arithmetic applied in a
+//! fixed order - no iteration over hash maps, and no platform dependent maths
+//! functions.
+
+use std::time::Duration;
+use net::{InputPacket, INPUT_LEFT, INPUT_RIGHT, INPUT_JUMP};
+
+/// Body flag for a body affected by gravity.
+pub const BODY_GRAVITY : u32 = 1;
+/// Body flag set by `step` for a body standing on something, so it can jump.
+pub const BODY_GROUNDED : u32 = 1 << 1;
+
+/// The acceleration due to gravity, in units per second squared. Y points
+/// down the screen.
+pub const GRAVITY : [f32; 2] = [0.0, 600.0];
+
+/// How fast a player walks, in units per second.
+pub const MOVE_SPEED : f32 = 200.0;
+/// The upwards speed a player jumps with, in units per second.
+pub const JUMP_SPEED : f32 = 400.0;
+/// The mass of a player, in KG.
+pub const PLAYER_MASS : f32 = 5.0;
+
+/// An axis-aligned bounding box - X, Y, W, H.
+pub type Aabb = [f32; 4];
+
+/// A physical body, for an object which can move.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Body {
+  /// Acceleration vector
+  pub acc: [f32; 2],
+  /// Velocity vector
+  pub vel: [f32; 2],
+  /// Mass in KG
+  pub mass: f32,
+  /// Bitflags indicating properties of this body
+  /// * BIT 0 - Gravity. 1 for this body to be affected by gravity, 0 for not.
+  /// * BIT 1 - Grounded. 1 if the body was standing on something after the
+  ///   last step, 0 for not.
+  pub flags: u32,
+}
+
+/// An object in the physics world. Objects without a body never move, and
+/// block the objects which do - the ground, for example.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Object {
+  pub aabb: Aabb,
+  pub body: Option<Body>,
+}
+
+impl Body {
+  /// The body of a player moving at a velocity.
+  pub fn player(vel: [f32; 2]) -> Body {
+    Body { acc: [0.0, 0.0], vel, mass: PLAYER_MASS, flags: BODY_GRAVITY }
+  }
+
+  /// Whether this body was standing on something after the last step.
+  pub fn is_grounded(&self) -> bool {
+    self.flags & BODY_GROUNDED != 0
+  }
+
+  /// Set or clear the grounded flag.
+  pub fn set_grounded(&mut self, grounded: bool) {
+    if grounded { self.flags |= BODY_GROUNDED; } else { self.flags &= !BODY_GROUNDED; }
+  }
+}
+
+impl AsMut<Object> for Object {
+  fn as_mut(&mut self) -> &mut Object {
+    self
+  }
+}
+
+/// The length of a tick in seconds, to step the world by. The server and
+/// client prediction must both use this, as step lengths rounded differently
+/// make the simulations drift apart.
+pub fn tick_len(tickrate: u32) -> f32 {
+  (Duration::from_secs(1) / tickrate).as_secs_f32()
+}
+
+/// Control a player's body with the input in effect for a tick. This is run
+/// before the tick is stepped.
+pub fn apply_input(body: &mut Body, input: &InputPacket) {
+  let mut x = 0.0;
+  if input.is_held(INPUT_LEFT) { x -= 1.0; }
+  if input.is_held(INPUT_RIGHT) { x += 1.0; }
+  // The analog stick is only used when no buttons are held
+  if let Some(axes) = input.axes.filter(|_| x == 0.0) { x = axes[0]; }
+  body.vel[0] = x * MOVE_SPEED;
+  if input.is_held(INPUT_JUMP) && body.is_grounded() { body.vel[1] = -JUMP_SPEED; }
+}
+
+/// Whether two AABBs overlap. AABBs which only touch don't overlap.
+pub fn overlaps(a: &Aabb, b: &Aabb) -> bool {
+  a[0] < b[0] + b[2] && b[0] < a[0] + a[2] && a[1] < b[1] + b[3] && b[1] < a[1] + a[3]
+}
+
+/// Step the world forward. Every body has gravity, its acceleration and its
+/// velocity applied, then overlapping objects are pushed apart. Bodies pushed
+/// up out of something are left grounded.
+/// # Params
+/// * `objects` - Every object in the world. Collisions are resolved in the
+///   order of this slice, so it must be the same wherever the step is run.
+/// * `dt` - The length of the step in seconds.
+pub fn step<T: AsMut<Object>>(objects: &mut [T], dt: f32) {
+  for o in objects.iter_mut() {
+    let o = o.as_mut();
+    let body = match o.body {
+      Some(ref mut body) => body,
+      None => continue,
+    };
+    body.set_grounded(false);
+    for (axis, gravity) in GRAVITY.iter().enumerate() {
+      if body.flags & BODY_GRAVITY != 0 { body.vel[axis] += gravity * dt; }
+      body.vel[axis] += body.acc[axis] * dt;
+      o.aabb[axis] += body.vel[axis] * dt;
+    }
+  }
+
+  for i in 0..objects.len() {
+    let (head, tail) = objects.split_at_mut(i + 1);
+    let a = head[i].as_mut();
+    for b in tail {
+      resolve_collision(a, b.as_mut());
+    }
+  }
+}
+
+/// Push two overlapping objects apart along the axis they overlap least on,
+/// stopping them moving into each other. If both can move, they're pushed in
+/// proportion to each other's mass.
+fn resolve_collision(a: &mut Object, b: &mut Object) {
+  if a.body.is_none() && b.body.is_none() { return; }
+  if !overlaps(&a.aabb, &b.aabb) { return; }
+
+  // How far each side would need to move to stop overlapping, along each
+  // axis. Positive pushes `b` towards increasing X or Y.
+  let push = |axis: usize| {
+    let forward = a.aabb[axis] + a.aabb[axis + 2] - b.aabb[axis];
+    let back = b.aabb[axis] + b.aabb[axis + 2] - a.aabb[axis];
+    if forward < back { forward } else { -back }
+  };
+  let (push_x, push_y) = (push(0), push(1));
+  let (axis, amount) = if push_x.abs() < push_y.abs() { (0, push_x) } else { (1, push_y) };
+
+  // The share of the push each object takes
+  let (share_a, share_b) = match (a.body, b.body) {
+    (Some(body_a), Some(body_b)) => {
+      let total = body_a.mass + body_b.mass;
+      (body_b.mass / total, body_a.mass / total)
+    }
+    (Some(_), None) => (1.0, 0.0),
+    _ => (0.0, 1.0),
+  };
+  a.aabb[axis] -= amount * share_a;
+  b.aabb[axis] += amount * share_b;
+
+  // Gravity pulls along increasing Y, so whichever object is on top is
+  // standing on the other
+  if axis == 1 {
+    let top = if amount > 0.0 { a.body.as_mut() } else { b.body.as_mut() };
+    if let Some(body) = top { body.set_grounded(true); }
+  }
+
+  // Stop them moving into each other. Two bodies end up moving together, as
+  // in a perfectly inelastic collision.
+  match (a.body.as_mut(), b.body.as_mut()) {
+    (Some(body_a), Some(body_b)) => {
+      let closing = (body_b.vel[axis] - body_a.vel[axis]) * amount.signum();
+      if closing < 0.0 {
+        let total = body_a.mass + body_b.mass;
+        let vel = (body_a.vel[axis] * body_a.mass + body_b.vel[axis] * body_b.mass) / total;
+        body_a.vel[axis] = vel;
+        body_b.vel[axis] = vel;
+      }
+    }
+    (Some(body_a), None) => {
+      if body_a.vel[axis] * amount.signum() > 0.0 { body_a.vel[axis] = 0.0; }
+    }
+    (None, Some(body_b)) => {
+      if body_b.vel[axis] * amount.signum() < 0.0 { body_b.vel[axis] = 0.0; }
+    }
+    (None, None) => (),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DT : f32 = 1.0 / 60.0;
+  /// Recorded results, which every build of the client and server must match.
+  const GOLDEN_TICK_LEN_30 : u32 = 0x3d08_8888;
+  const GOLDEN_TICK_LEN_60 : u32 = 0x3c88_8888;
+  const GOLDEN_TICK_LEN_120 : u32 = 0x3c08_8888;
+  const GOLDEN_CHECKSUM : u32 = 0x0cfb_1fd7;
+
+  fn body(vel: [f32; 2], mass: f32) -> Body {
+    Body { acc: [0.0, 0.0], vel, mass, flags: BODY_GRAVITY }
+  }
+
+  /// A few bodies falling onto the ground and into each other.
+  fn world() -> Vec<Object> {
+    vec![
+      Object { aabb: [0.0, 500.0, 800.0, 100.0], body: None },
+      Object { aabb: [100.0, 0.0, 32.0, 32.0], body: Some(body([40.0, 0.0], 5.0)) },
+      Object { aabb: [180.0, 20.0, 32.0, 32.0], body: Some(body([-40.0, -10.0], 2.0)) },
+      Object {
+        aabb: [400.0, 300.0, 16.0, 48.0],
+        body: Some(Body { acc: [25.0, 0.0], vel: [0.0, 0.0], mass: 1.0, flags: 0 }),
+      },
+    ]
+  }
+
+  fn to_bits(objects: &[Object]) -> Vec<u32> {
+    let mut bits = Vec::new();
+    for o in objects {
+      bits.extend(o.aabb.iter().map(|f| f.to_bits()));
+      if let Some(ref body) = o.body {
+        bits.extend(body.vel.iter().chain(&body.acc).map(|f| f.to_bits()));
+        bits.push(body.flags);
+      }
+    }
+    bits
+  }
+
+  #[test]
+  fn identical_runs_are_bit_for_bit_identical() {
+    let run = || {
+      let mut objects = world();
+      for _ in 0..600 { step(&mut objects, DT); }
+      to_bits(&objects)
+    };
+    assert_eq!(run(), run());
+  }
+
+  /// A checksum of a world's state, to compare against a recorded value.
+  fn checksum(objects: &[Object]) -> u32 {
+    to_bits(objects).iter().fold(0u32, |sum, &bits| sum.rotate_left(5) ^ bits)
+  }
+
+  #[test]
+  fn tick_len_is_pinned() {
+    // A step length rounded differently anywhere would make the client and
+    // server drift apart, so the exact values are recorded
+    assert_eq!(tick_len(30).to_bits(), GOLDEN_TICK_LEN_30);
+    assert_eq!(tick_len(60).to_bits(), GOLDEN_TICK_LEN_60);
+    assert_eq!(tick_len(120).to_bits(), GOLDEN_TICK_LEN_120);
+  }
+
+  #[test]
+  fn steps_match_the_recorded_world() {
+    let mut objects = world();
+    for _ in 0..600 { step(&mut objects, tick_len(60)); }
+    assert_eq!(checksum(&objects), GOLDEN_CHECKSUM);
+  }
+
+  #[test]
+  fn falling_body_lands_on_the_ground() {
+    let mut objects = vec![
+      Object { aabb: [0.0, 500.0, 800.0, 100.0], body: None },
+      Object { aabb: [100.0, 400.0, 32.0, 32.0], body: Some(body([0.0, 0.0], 5.0)) },
+    ];
+    for _ in 0..120 { step(&mut objects, DT); }
+    let o = &objects[1];
+    assert!((o.aabb[1] + o.aabb[3] - 500.0).abs() < 1.0, "landed at {:?}", o.aabb);
+    assert!(o.body.unwrap().vel[1] < 20.0);
+    assert!(o.body.unwrap().is_grounded());
+    assert_eq!(objects[0].aabb, [0.0, 500.0, 800.0, 100.0]);
+  }
+
+  #[test]
+  fn only_grounded_bodies_can_jump() {
+    let jump = InputPacket::new(0, INPUT_JUMP);
+    let mut objects = vec![
+      Object { aabb: [0.0, 500.0, 800.0, 100.0], body: None },
+      Object { aabb: [100.0, 400.0, 32.0, 32.0], body: Some(Body::player([0.0, 0.0])) },
+    ];
+    // Mid-air, even at the top of an arc where the body isn't moving
+    apply_input(objects[1].body.as_mut().unwrap(), &jump);
+    assert_eq!(objects[1].body.unwrap().vel[1], 0.0);
+
+    for _ in 0..120 { step(&mut objects, DT); }
+    // Resting on the ground doesn't leave the body's velocity at exactly 0
+    let body = objects[1].body.as_mut().unwrap();
+    assert!(body.is_grounded());
+    apply_input(body, &jump);
+    assert_eq!(body.vel[1], -JUMP_SPEED);
+    step(&mut objects, DT);
+    assert!(!objects[1].body.unwrap().is_grounded());
+  }
+
+  #[test]
+  fn body_standing_on_a_body_is_grounded() {
+    let mut objects = vec![
+      Object { aabb: [0.0, 500.0, 800.0, 100.0], body: None },
+      Object { aabb: [100.0, 468.0, 32.0, 32.0], body: Some(Body::player([0.0, 0.0])) },
+      Object { aabb: [100.0, 436.0, 32.0, 32.0], body: Some(Body::player([0.0, 0.0])) },
+    ];
+    for _ in 0..60 { step(&mut objects, DT); }
+    assert!(objects[1].body.unwrap().is_grounded());
+    assert!(objects[2].body.unwrap().is_grounded());
+  }
+
+  #[test]
+  fn bodies_are_pushed_apart_by_mass() {
+    let mut objects = vec![
+      Object {
+        aabb: [0.0, 0.0, 10.0, 10.0],
+        body: Some(Body { acc: [0.0, 0.0], vel: [0.0, 0.0], mass: 3.0, flags: 0 }),
+      },
+      Object {
+        aabb: [6.0, 0.0, 10.0, 10.0],
+        body: Some(Body { acc: [0.0, 0.0], vel: [0.0, 0.0], mass: 1.0, flags: 0 }),
+      },
+    ];
+    step(&mut objects, DT);
+    assert!(!overlaps(&objects[0].aabb, &objects[1].aabb));
+    assert_eq!(objects[0].aabb[0], -1.0);
+    assert_eq!(objects[1].aabb[0], 9.0);
+  }
+}
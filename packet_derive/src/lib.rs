@@ -0,0 +1,157 @@
+//! Derive macros for the `Wire` and `Packet` traits in `common::net`.
+//!
+//! `#[derive(Wire)]` encodes every field of a struct in declaration order
+//! using the field's own `Wire` implementation. `#[derive(Packet)]` does the
+//! same, and additionally implements `Packet` with the tag given in a
+//! `#[packet(tag = "abc")]` attribute, so packets can also be nested inside
+//! other packets.
+//!
+//! Float fields can be quantized by marking them with
+//! `#[packet(quantize = QUANTIZER)]`, where `QUANTIZER` is an expression
+//! evaluating to a `common::net::Quantizer`.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::{Data, DeriveInput, Expr, Field, Fields, Index, LitStr};
+
+#[proc_macro_derive(Wire, attributes(packet))]
+pub fn derive_wire(input: TokenStream) -> TokenStream {
+  let input = syn::parse_macro_input!(input as DeriveInput);
+  match impl_wire(&input) {
+    Ok(tokens) => tokens.into(),
+    Err(e) => e.to_compile_error().into(),
+  }
+}
+
+#[proc_macro_derive(Packet, attributes(packet))]
+pub fn derive_packet(input: TokenStream) -> TokenStream {
+  let input = syn::parse_macro_input!(input as DeriveInput);
+  let res = impl_wire(&input).and_then(|wire| {
+    let packet = impl_packet(&input)?;
+    Ok(quote! { #wire #packet })
+  });
+  match res {
+    Ok(tokens) => tokens.into(),
+    Err(e) => e.to_compile_error().into(),
+  }
+}
+
+/// Generate the `Wire` impl for a struct.
+fn impl_wire(input: &DeriveInput) -> syn::Result<TokenStream2> {
+  let name = &input.ident;
+  let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+  let data = match input.data {
+    Data::Struct(ref data) => data,
+    _ => return Err(syn::Error::new_spanned(input, "packets must be structs")),
+  };
+
+  let (encode, decode) = match data.fields {
+    Fields::Named(ref fields) => {
+      let mut encodes = Vec::new();
+      let mut decodes = Vec::new();
+      for field in &fields.named {
+        let ident = field.ident.clone().unwrap();
+        let (encode, decode) = field_codec(field, quote! { self.#ident })?;
+        encodes.push(encode);
+        decodes.push(quote! { #ident: #decode });
+      }
+      (quote! { #(#encodes)* }, quote! { #name { #(#decodes),* } })
+    }
+    Fields::Unnamed(ref fields) => {
+      let mut encodes = Vec::new();
+      let mut decodes = Vec::new();
+      for (i, field) in fields.unnamed.iter().enumerate() {
+        let index = Index::from(i);
+        let (encode, decode) = field_codec(field, quote! { self.#index })?;
+        encodes.push(encode);
+        decodes.push(decode);
+      }
+      (quote! { #(#encodes)* }, quote! { #name(#(#decodes),*) })
+    }
+    Fields::Unit => (quote! {}, quote! { #name }),
+  };
+
+  Ok(quote! {
+    impl #impl_generics ::common::net::Wire for #name #ty_generics #where_clause {
+      #[allow(unused_variables)]
+      fn encode(&self, buf: &mut Vec<u8>) {
+        #encode
+      }
+      #[allow(unused_variables)]
+      fn decode(r: &mut ::common::net::WireReader)
+          -> Result<Self, ::common::net::DeserialiseError> {
+        Ok(#decode)
+      }
+    }
+  })
+}
+
+/// Generate the statements encoding and the expression decoding a single
+/// field, accessed through `access`.
+fn field_codec(field: &Field, access: TokenStream2) -> syn::Result<(TokenStream2, TokenStream2)> {
+  let mut quantizer : Option<Expr> = None;
+  for attr in field.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("quantize") {
+        quantizer = Some(meta.value()?.parse()?);
+        Ok(())
+      } else {
+        Err(meta.error("unknown packet field attribute"))
+      }
+    })?;
+  }
+  Ok(match quantizer {
+    Some(q) => (quote! { ::common::net::Quantizer::encode(&(#q), &#access, buf); },
+                quote! { ::common::net::Quantizer::decode(&(#q), r)? }),
+    None => (quote! { ::common::net::Wire::encode(&#access, buf); },
+             quote! { ::common::net::Wire::decode(r)? }),
+  })
+}
+
+/// Generate the `Packet` impl for a struct, reading the tag from the
+/// `#[packet(tag = "...")]` attribute.
+fn impl_packet(input: &DeriveInput) -> syn::Result<TokenStream2> {
+  let name = &input.ident;
+  let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+  let mut tag = None;
+  for attr in input.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("tag") {
+        let lit : LitStr = meta.value()?.parse()?;
+        if lit.value().len() != 3 {
+          return Err(syn::Error::new_spanned(&lit, "packet tags must be exactly 3 bytes"));
+        }
+        tag = Some(lit);
+        Ok(())
+      } else {
+        Err(meta.error("unknown packet attribute"))
+      }
+    })?;
+  }
+  let tag = match tag {
+    Some(tag) => tag,
+    None => return Err(syn::Error::new_spanned(input, "missing #[packet(tag = \"...\")] attribute")),
+  };
+  let tag_bytes = syn::LitByteStr::new(tag.value().as_bytes(), tag.span());
+
+  Ok(quote! {
+    impl #impl_generics ::common::net::Packet for #name #ty_generics #where_clause {
+      const TAG: ::common::net::Tag = *#tag_bytes;
+
+      fn serialise_body(&self, buf: &mut Vec<u8>) {
+        ::common::net::Wire::encode(self, buf);
+      }
+
+      fn deserialise(buf: &[u8]) -> Result<Self, ::common::net::DeserialiseError> {
+        ::common::net::decode_body(buf)
+      }
+    }
+  })
+}
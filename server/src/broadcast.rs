@@ -0,0 +1,60 @@
+//! Helpers for sending packets to groups of clients. Packets are only queued
+//! on each client, and written to the sockets by the main loop, so nothing
+//! here blocks or touches a socket.
+
+use std::time::Instant;
+use clients::ClientList;
+use room::Room;
+use common::net::{Packet, Channel};
+
+/// Send a packet over TCP to each of a list of clients.
+pub fn send_to<P: Packet>(client_list: &mut ClientList, ids: &[usize], packet: &P) {
+  for &id in ids {
+    let c = match client_list.get_mut(id) {
+      Some(c) => c,
+      None => continue,
+    };
+    if let Err(e) = c.send_tcp(packet) {
+      println!("Failed to send {} packet to client {}: {}",
+               String::from_utf8_lossy(&P::TAG), c.id, e);
+    }
+  }
+}
+
+/// Send a packet over TCP to every player in a room.
+pub fn broadcast_room<P: Packet>(client_list: &mut ClientList, room: &Room, packet: &P) {
+  send_to(client_list, &room.players, packet);
+}
+
+/// Send every player in a room a snapshot of its world over UDP, each encoded
+/// against the last snapshot the player acknowledged.
+pub fn broadcast_snapshot(client_list: &mut ClientList, room: &Room, now: Instant) {
+  let snapshot = room.snapshot();
+  for &id in &room.players {
+    let c = match client_list.get_mut(id) {
+      Some(ref c) if c.udp_addr.is_none() => continue,
+      Some(c) => c,
+      None => continue,
+    };
+    // Snapshots are unreliable, so one split into fragments would be lost if
+    // any fragment was. Snapshots which don't fit in a datagram are dropped.
+    let frame = match c.delta_snapshot(&snapshot).encode_datagram() {
+      Ok(frame) => frame,
+      Err(e) => {
+        println!("Dropping snapshot for client {}: {}", c.id, e);
+        continue;
+      }
+    };
+    if let Err(e) = c.udp_conn.send(Channel::Unreliable, &frame, now) {
+      println!("Failed to send snapshot to client {}: {}", c.id, e);
+    }
+  }
+}
+
+/// Send every player in a room the state of its lobby.
+pub fn broadcast_lobby(client_list: &mut ClientList, room: &Room) {
+  let state = room.lobby_state(|id| {
+    client_list.get(id).map(|c| c.name.clone()).unwrap_or_default()
+  });
+  broadcast_room(client_list, room, &state);
+}
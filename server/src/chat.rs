@@ -0,0 +1,167 @@
+//! Server side checks on chat messages - length and rate limits, and
+//! filtering out unwanted words.
+
+use std::fs;
+use std::io;
+use std::time::Instant;
+use common::net::MAX_CHAT_LEN;
+
+/// The number of messages a client can send in a burst.
+pub const CHAT_BURST : f64 = 5.0;
+/// The number of messages per second a client can keep sending at.
+pub const CHAT_RATE : f64 = 1.0;
+
+/// A word list the server filters chat with, one word per line. If it doesn't
+/// exist chat isn't filtered.
+pub const CHAT_FILTER_PATH : &str = "chat_filter.txt";
+
+/// Filters unwanted words out of chat messages. Implement this to change how
+/// chat is filtered.
+pub trait ProfanityFilter {
+  /// # Returns
+  /// The message with anything unwanted removed or masked.
+  fn filter(&self, text: &str) -> String;
+}
+
+/// A filter which lets everything through.
+pub struct NoFilter;
+
+impl ProfanityFilter for NoFilter {
+  fn filter(&self, text: &str) -> String {
+    text.to_owned()
+  }
+}
+
+/// A filter which masks every word in a list with asterisks, ignoring case.
+/// Only whole words are masked, so innocent words containing a blocked word
+/// are left alone.
+pub struct WordFilter {
+  /// Lowercase blocked words.
+  words: Vec<String>,
+}
+
+impl WordFilter {
+  pub fn new<S: AsRef<str>>(words: &[S]) -> WordFilter {
+    WordFilter { words: words.iter().map(|w| w.as_ref().trim().to_lowercase())
+                              .filter(|w| !w.is_empty()).collect() }
+  }
+
+  /// Load a word list from a file, with one word per line.
+  pub fn from_file(path: &str) -> io::Result<WordFilter> {
+    let list = fs::read_to_string(path)?;
+    Ok(WordFilter::new(&list.lines().collect::<Vec<_>>()))
+  }
+}
+
+impl ProfanityFilter for WordFilter {
+  fn filter(&self, text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut word = String::new();
+    // Append a word to the output, masking it if it's blocked
+    let flush = |word: &mut String, ret: &mut String| {
+      if self.words.contains(&word.to_lowercase()) {
+        ret.extend(word.chars().map(|_| '*'));
+      } else {
+        ret.push_str(word);
+      }
+      word.clear();
+    };
+    for c in text.chars() {
+      if c.is_alphanumeric() {
+        word.push(c);
+      } else {
+        flush(&mut word, &mut ret);
+        ret.push(c);
+      }
+    }
+    flush(&mut word, &mut ret);
+    ret
+  }
+}
+
+/// Limits how often a client can send messages, allowing short bursts.
+pub struct RateLimiter {
+  /// Messages which can be sent right now.
+  tokens: f64,
+  last: Instant,
+}
+
+impl RateLimiter {
+  pub fn new(now: Instant) -> RateLimiter {
+    RateLimiter { tokens: CHAT_BURST, last: now }
+  }
+
+  /// Check whether another message can be sent, and count it if so.
+  pub fn try_send(&mut self, now: Instant) -> bool {
+    let elapsed = now.duration_since(self.last).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * CHAT_RATE).min(CHAT_BURST);
+    self.last = now;
+    if self.tokens < 1.0 { return false; }
+    self.tokens -= 1.0;
+    true
+  }
+}
+
+/// Tidy up a chat message, removing control characters and surrounding
+/// whitespace. Tabs and newlines become spaces, so they still separate words.
+/// # Returns
+/// The tidied message, or why it can't be sent.
+pub fn clean_message(text: &str) -> Result<String, &'static str> {
+  let text : String = text.chars()
+    .map(|c| if c.is_control() && c.is_whitespace() { ' ' } else { c })
+    .filter(|c| !c.is_control())
+    .collect();
+  let text = text.trim();
+  if text.is_empty() { return Err("message is empty"); }
+  if text.chars().count() > MAX_CHAT_LEN { return Err("message is too long"); }
+  Ok(text.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn blocked_words_are_masked_ignoring_case() {
+    let filter = WordFilter::new(&["darn", " heck\r", ""]);
+    assert_eq!(filter.filter("Darn it, what the HECK!"), "**** it, what the ****!");
+  }
+
+  #[test]
+  fn only_whole_words_are_masked() {
+    let filter = WordFilter::new(&["ass"]);
+    assert_eq!(filter.filter("a classic pass, ass"), "a classic pass, ***");
+  }
+
+  #[test]
+  fn no_filter_changes_nothing() {
+    assert_eq!(NoFilter.filter("anything goes"), "anything goes");
+  }
+
+  #[test]
+  fn rate_limiter_allows_a_burst_then_refills() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(start);
+    for _ in 0..CHAT_BURST as usize { assert!(limiter.try_send(start)); }
+    assert!(!limiter.try_send(start));
+
+    // One message's worth of time refills one message
+    let later = start + Duration::from_secs_f64(1.0 / CHAT_RATE);
+    assert!(limiter.try_send(later));
+    assert!(!limiter.try_send(later));
+
+    // Waiting a long time doesn't allow more than a burst
+    let much_later = later + Duration::from_secs(3600);
+    for _ in 0..CHAT_BURST as usize { assert!(limiter.try_send(much_later)); }
+    assert!(!limiter.try_send(much_later));
+  }
+
+  #[test]
+  fn messages_are_cleaned_and_length_checked() {
+    assert_eq!(clean_message("  hi\u{7}\tthere \n"), Ok("hi there".to_owned()));
+    assert!(clean_message(" \n\t").is_err());
+    assert!(clean_message(&"é".repeat(MAX_CHAT_LEN)).is_ok());
+    assert!(clean_message(&"a".repeat(MAX_CHAT_LEN + 1)).is_err());
+  }
+}
@@ -1,74 +1,558 @@
 //! A module for representing connected clients in memory, and storing their
 //! associated data.
 
-use mio::net::TcpStream;
+use mio::net::{TcpStream, UdpSocket};
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
 use std::collections::VecDeque;
-use common::net::{RegPacket, Packet};
+use std::time::{Duration, Instant};
+use rand::{Rng, OsRng};
+use chat::RateLimiter;
+use config::Config;
+use common::net::{AnyPacket, Packet, Frame, FrameEncoder, FrameDecoder, DeserialiseError,
+                  SerialiseError, SnapshotPacket, DeltaSnapshotPacket, SNAPSHOT_HISTORY_LEN,
+                  Connection, Channel, RegAckPacket, RegRejectPacket, HelloPacket,
+                  HelloAckPacket, HelloRejectPacket, PROTOCOL_VERSION, negotiate, PingPacket,
+                  DisconnectPacket};
+
+/// Generate a new session token from the OS's secure random number
+/// generator, so tokens can't be predicted from ones handed out before.
+fn new_session_token() -> io::Result<u64> {
+  Ok(OsRng::new()?.next_u64())
+}
 
 /// A struct representing a client.
 pub struct Client {
   /// The ID of this client.
   pub id: usize,
-  /// The name of this client.
+  /// The protocol features agreed with this client, or `None` if it hasn't
+  /// sent a compatible `HelloPacket` yet.
+  pub features: Option<u32>,
+  /// Why the client is being disconnected. Once set, the client is dropped
+  /// after the packets already received have been parsed.
+  pub disconnected: Option<String>,
+  /// When the client connected.
+  pub connected_at: Instant,
+  /// When we last received anything from the client, over TCP or UDP.
+  pub last_heard: Instant,
+  /// When we last pinged the client.
+  pub last_ping: Instant,
+  /// The sequence number of the last ping sent.
+  pub ping_seq: u32,
+
+  /// The name of this client. Empty until the client has registered.
   pub name: String,
+  /// Whether a registration is waiting in `server_packets` to be accepted or
+  /// rejected.
+  pub registering: bool,
+  /// The room this client is in, if any.
+  pub room: Option<u32>,
+  /// Limits how quickly this client can send chat messages.
+  pub chat_limiter: RateLimiter,
+  /// Packets which need more than this client to handle, such as checking
+  /// a name against the other clients' or joining a room. These are handled
+  /// by the main loop.
+  pub server_packets: Vec<AnyPacket>,
 
-  /// The address of this client for UDP datagtrams.
-  pub udp_addr: SocketAddr,
-  /// A buffer of data not yet parsed by this client which arrived through UDP.
-  pub udp_buf: VecDeque<u8>,
+  /// The token the client must send in a `UdpHelloPacket` to bind its UDP
+  /// address. Issued when the client registers.
+  pub session_token: Option<u64>,
+  /// The address of this client for UDP datagrams, or `None` if the client
+  /// hasn't sent a `UdpHelloPacket` yet.
+  pub udp_addr: Option<SocketAddr>,
+  /// The connection state for UDP datagrams to and from this client.
+  pub udp_conn: Connection,
+  /// Datagrams waiting to be written to the UDP socket once it's writable.
+  pub udp_out: VecDeque<Vec<u8>>,
 
   /// The stream to write to to send TCP messages to this client.
   pub tcp_stream: TcpStream,
-  /// A buffer of data not yet parsed by this client which arrived through TCP.
-  pub tcp_buf: VecDeque<u8>,
+  /// Decoder holding data not yet parsed by this client which arrived through
+  /// TCP.
+  pub tcp_decoder: FrameDecoder,
+  /// Data waiting to be written to the TCP stream once it's writable.
+  pub tcp_out: Vec<u8>,
+
+  /// Snapshots recently sent to this client, oldest first. These are the
+  /// baselines which deltas can be encoded against.
+  pub snapshot_history: VecDeque<SnapshotPacket>,
+  /// The tick of the latest snapshot this client has acknowledged.
+  pub acked_snapshot: Option<u32>,
 }
 
 impl Client {
-  /// Function to create a new client with a name and TCP stream. The peer
-  /// address of the TCP stream is used to generate a UDP address, by
-  /// subtracting 1 from the value of the port.
+  /// Function to create a new client with a name and TCP stream. The client
+  /// has no UDP address until it registers and binds one with its session
+  /// token.
   /// # Params
   /// * `id` - The ID of the client. Must be unique.
   /// * `name` - The name of this client - the client should pass this through
-  ///            the TCP stream to 'register'.
+  ///   the TCP stream to 'register'.
   /// * `tcp_stream` - The TCP stream linked to the client.
-  pub fn new(id: usize, name: &str, tcp_stream: TcpStream) -> Client {
-    // UDP address port is always 1 below the TCP address, so get the udp address
-    let mut udp_addr = tcp_stream.peer_addr().unwrap();
-    let udp_port = udp_addr.port() - 1;
-    udp_addr.set_port(udp_port);
-
-    // Create and return the client object
+  /// * `now` - The current time.
+  pub fn new(id: usize, name: &str, tcp_stream: TcpStream, now: Instant) -> Client {
     Client {
-      id: id,
+      id,
+      features: None,
+      disconnected: None,
+      connected_at: now,
+      last_heard: now,
+      last_ping: now,
+      ping_seq: 0,
       name: name.to_owned(),
-      udp_addr: udp_addr,
-      udp_buf: VecDeque::new(),
-      tcp_stream: tcp_stream,
-      tcp_buf: VecDeque::new(),
+      registering: false,
+      room: None,
+      chat_limiter: RateLimiter::new(now),
+      server_packets: Vec::new(),
+      session_token: None,
+      udp_addr: None,
+      udp_conn: Connection::new(),
+      udp_out: VecDeque::new(),
+      tcp_stream,
+      tcp_decoder: FrameDecoder::default(),
+      tcp_out: Vec::new(),
+      snapshot_history: VecDeque::with_capacity(SNAPSHOT_HISTORY_LEN),
+      acked_snapshot: None,
+    }
+  }
+
+  /// Encode a snapshot for this client as a delta against the last snapshot
+  /// it acknowledged. If the client hasn't acknowledged a snapshot, or the
+  /// acknowledged one has fallen out of the history, a full snapshot is
+  /// encoded instead. The snapshot is recorded as a future baseline.
+  pub fn delta_snapshot(&mut self, snapshot: &SnapshotPacket) -> DeltaSnapshotPacket {
+    let delta = {
+      let baseline = self.acked_snapshot
+        .and_then(|tick| self.snapshot_history.iter().find(|s| s.tick == tick));
+      DeltaSnapshotPacket::diff(baseline, snapshot)
+    };
+    if self.snapshot_history.len() == SNAPSHOT_HISTORY_LEN {
+      self.snapshot_history.pop_front();
+    }
+    self.snapshot_history.push_back(snapshot.clone());
+    delta
+  }
+
+  /// Record that the client has received the snapshot with the given tick.
+  /// Acks for snapshots older than the latest ack, or which were never sent,
+  /// are ignored.
+  pub fn ack_snapshot(&mut self, tick: u32) {
+    if self.acked_snapshot.is_some_and(|acked| acked >= tick) { return; }
+    if self.snapshot_history.iter().any(|s| s.tick == tick) {
+      self.acked_snapshot = Some(tick);
+    }
+  }
+
+  /// Whether the client has successfully registered.
+  pub fn is_registered(&self) -> bool {
+    !self.name.is_empty()
+  }
+
+  /// Whether the client has registered and bound its UDP address.
+  pub fn is_handshake_complete(&self) -> bool {
+    self.is_registered() && self.udp_addr.is_some()
+  }
+
+  /// Tell the client why it's being disconnected, and mark it to be dropped.
+  /// Does nothing if the client is already being disconnected.
+  pub fn disconnect(&mut self, reason: &str) {
+    if self.disconnected.is_some() { return; }
+    // The client may already be gone, so failing to send is fine
+    let _ = self.send_tcp(&DisconnectPacket { reason: reason.to_owned() });
+    self.disconnected = Some(reason.to_owned());
+  }
+
+  /// Disconnect the client if it's taken too long to finish the handshake, or
+  /// we haven't heard from it in too long.
+  pub fn check_timeouts(&mut self, now: Instant, config: &Config) {
+    if !self.is_handshake_complete() && now.duration_since(self.connected_at) > config.handshake_timeout {
+      self.disconnect("handshake timed out");
+    } else if now.duration_since(self.last_heard) > config.idle_timeout {
+      self.disconnect("timed out");
+    }
+  }
+
+  /// Ping the client over UDP if it's been long enough since the last ping.
+  pub fn heartbeat(&mut self, now: Instant, interval: Duration) {
+    if self.udp_addr.is_none() || now.duration_since(self.last_ping) < interval { return; }
+    self.last_ping = now;
+    self.ping_seq = self.ping_seq.wrapping_add(1);
+    let ping = PingPacket { seq: self.ping_seq };
+    if let Err(e) = self.send_udp(Channel::Unreliable, &ping, now) {
+      println!("Failed to ping client {}: {}", self.id, e);
+    }
+  }
+
+  /// Read everything the client has sent over TCP into the decoder. The
+  /// stream is edge-triggered, so this reads until it would block. If the
+  /// stream has been closed, the client is marked to be dropped.
+  /// # Params
+  /// * `buf` - A buffer to read into, reused between reads.
+  /// * `now` - The current time.
+  pub fn read_tcp(&mut self, buf: &mut [u8], now: Instant) {
+    let reason = loop {
+      match self.tcp_stream.read(buf) {
+        // Reaching the end of the stream means it was closed
+        Ok(0) => break "connection closed".to_owned(),
+        Ok(len) => {
+          self.last_heard = now;
+          self.tcp_decoder.push(&buf[..len]);
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => break format!("connection error: {}", e),
+      }
+    };
+    if self.disconnected.is_none() { self.disconnected = Some(reason); }
+  }
+
+  /// Write as much queued TCP data as the stream will take without blocking.
+  /// Whatever's left is written when the stream next becomes writable.
+  pub fn flush_tcp(&mut self) -> io::Result<()> {
+    while !self.tcp_out.is_empty() {
+      match self.tcp_stream.write(&self.tcp_out) {
+        Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+        Ok(len) => { self.tcp_out.drain(..len); }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  }
+
+  /// Accept the client's registration, issuing a session token and sending it
+  /// to the client along with this client's ID.
+  pub fn accept_registration(&mut self, name: String) {
+    self.registering = false;
+    let session_token = match new_session_token() {
+      Ok(token) => token,
+      Err(e) => {
+        println!("Failed to generate a session token for client {}: {}", self.id, e);
+        self.reject_registration("the server couldn't start a session, try again");
+        return;
+      }
+    };
+    println!("Registered client {} as \"{}\"", self.id, name);
+    self.name = name;
+    self.session_token = Some(session_token);
+    let ack = RegAckPacket { client_id: self.id as u32, session_token };
+    if let Err(e) = self.send_tcp(&ack) {
+      println!("Failed to send reg ack to client {}: {}", self.id, e);
+    }
+  }
+
+  /// Reject the client's registration, telling it why.
+  pub fn reject_registration(&mut self, reason: &str) {
+    println!("Rejected registration from client {}: {}", self.id, reason);
+    if let Err(e) = self.send_tcp(&RegRejectPacket { reason: reason.to_owned() }) {
+      println!("Failed to send reg reject to client {}: {}", self.id, e);
+    }
+  }
+
+  /// Bind the address UDP datagrams are sent to and accepted from. Called
+  /// when a `UdpHelloPacket` with this client's session token arrives, so
+  /// this also follows the client if its address changes. Bind through
+  /// `ClientList::bind_udp`, which keeps its address lookup in sync.
+  pub fn bind_udp(&mut self, addr: SocketAddr) {
+    if self.udp_addr != Some(addr) {
+      println!("Bound client {} to UDP address {}", self.id, addr);
+      self.udp_addr = Some(addr);
+    }
+  }
+
+  /// Queue a packet to be sent to this client over TCP. It's written to the
+  /// stream by `flush_tcp`.
+  pub fn send_tcp<P: Packet>(&mut self, packet: &P) -> io::Result<()> {
+    let frame = FrameEncoder::default().encode(packet)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    self.tcp_out.extend_from_slice(&frame);
+    Ok(())
+  }
+
+  /// Queue a packet to be sent to this client over UDP, fragmenting it if
+  /// needed. The datagrams are written to the socket by `queue_datagrams` and
+  /// `flush_udp`.
+  pub fn send_udp<P: Packet>(&mut self, channel: Channel, packet: &P, now: Instant)
+      -> Result<(), SerialiseError> {
+    let frame = FrameEncoder::message().encode(packet)?;
+    self.udp_conn.send(channel, &frame, now)
+  }
+
+  /// Move any datagrams due to be sent or resent from `udp_conn` to the
+  /// outgoing queue. Does nothing until the UDP address is bound.
+  pub fn queue_datagrams(&mut self, now: Instant) {
+    if self.udp_addr.is_none() { return; }
+    self.udp_conn.update(now);
+    while let Some(datagram) = self.udp_conn.poll_datagram() {
+      self.udp_out.push_back(datagram);
+    }
+  }
+
+  /// Write as many queued datagrams as the socket will take without blocking.
+  /// Datagrams which fail for any other reason are dropped, like any other
+  /// lost datagram.
+  pub fn flush_udp(&mut self, socket: &UdpSocket) {
+    let addr = match self.udp_addr {
+      Some(addr) => addr,
+      None => return,
+    };
+    while let Some(datagram) = self.udp_out.pop_front() {
+      match socket.send_to(&datagram, &addr) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+          self.udp_out.push_front(datagram);
+          break;
+        }
+        Err(e) => println!("Failed to send UDP datagram to client {}: {}", self.id, e),
+      }
     }
   }
 
-  /// A function to check whether there are any packets to parse in the tcp or
-  /// udp buffer.
-  pub fn try_parse_packets(&mut self) {
-    use std::mem::transmute;
+  /// The number of bytes waiting to be written to this client's sockets.
+  pub fn queued_bytes(&self) -> usize {
+    self.tcp_out.len() + self.udp_out.iter().map(|d| d.len()).sum::<usize>()
+  }
+
+  /// Mark the client to be dropped if more than `limit` bytes are still
+  /// waiting to be sent to it, as it isn't keeping up with the server.
+  pub fn check_send_queue(&mut self, limit: usize) {
+    if self.queued_bytes() > limit && self.disconnected.is_none() {
+      self.disconnected = Some("not keeping up with the server".to_owned());
+    }
+  }
+
+  /// Process a datagram received from this client's UDP address. Datagrams
+  /// are easily spoofed, so bad ones are logged and dropped rather than
+  /// dropping the client.
+  pub fn receive_datagram(&mut self, datagram: &[u8], now: Instant) {
+    let messages = match self.udp_conn.receive(datagram, now) {
+      Ok(messages) => {
+        self.last_heard = now;
+        messages
+      }
+      Err(e) => {
+        println!("Ignoring bad datagram from client {}: {}", self.id, e);
+        return;
+      }
+    };
+    for (_, message) in messages {
+      match Frame::from_message(&message).and_then(|f| AnyPacket::from_frame(&f)) {
+        Ok(packet) => self.handle_packet(packet),
+        Err(e) => println!("Ignoring bad message from client {}: {}", self.id, e),
+      }
+    }
+  }
+
+  /// A function to check whether there are any packets to parse in the tcp
+  /// buffer.
+  /// # Returns
+  /// An error if the client sent malformed data. The stream can't be
+  /// resynchronised after this, so the client should be dropped.
+  pub fn try_parse_packets(&mut self) -> Result<(), DeserialiseError> {
     // Check TCP
-    loop {
-      if self.tcp_buf.len() < 7 { break; }
-
-      let packet_size_bytes = [self.tcp_buf[0], self.tcp_buf[1], self.tcp_buf[2], self.tcp_buf[3]];
-      let packet_type = [self.tcp_buf[4], self.tcp_buf[5], self.tcp_buf[6]];
-      let packet_size = unsafe { transmute::<[u8; 4], u32>(packet_size_bytes) };
-      if self.tcp_buf.len() - 7 < packet_size as usize { break; }
-      // Consume these bytes, and get the body of the packet
-      let packet_body = self.tcp_buf.drain(0..(packet_size+7) as usize)
-        .skip(7).collect::<Vec<u8>>();
-      if packet_type[..] == *("reg".as_bytes()) {
-        let reg_packet = RegPacket::deserialise(&packet_body[..]).ok().unwrap();
+    while let Some(frame) = self.tcp_decoder.next_frame()? {
+      let packet = AnyPacket::from_frame(&frame)?;
+      self.handle_packet(packet);
+    }
+    Ok(())
+  }
+
+  /// Check the client's protocol version, and agree on the features to use.
+  /// Incompatible clients are sent the reason, and marked to be dropped.
+  fn handle_hello(&mut self, hello: HelloPacket) {
+    match negotiate(hello.version, hello.features) {
+      Ok(features) => {
+        self.features = Some(features);
+        if let Err(e) = self.send_tcp(&HelloAckPacket { version: PROTOCOL_VERSION, features }) {
+          println!("Failed to send hello ack to client {}: {}", self.id, e);
+        }
+      }
+      Err(reason) => self.reject(&reason),
+    }
+  }
+
+  /// Send a client which failed the handshake the reason, and mark it to be
+  /// dropped.
+  fn reject(&mut self, reason: &str) {
+    println!("Rejecting client {}: {}", self.id, reason);
+    if let Err(e) = self.send_tcp(&HelloRejectPacket { reason: reason.to_owned() }) {
+      println!("Failed to send hello reject to client {}: {}", self.id, e);
+    }
+    self.disconnected = Some(format!("rejected: {}", reason));
+  }
+
+  /// Handle a packet received over either TCP or UDP.
+  fn handle_packet(&mut self, packet: AnyPacket) {
+    match packet {
+      AnyPacket::Disconnect(disconnect) => {
+        self.disconnected = Some(format!("left: {}", disconnect.reason));
+      }
+      // Anything else from a client which is leaving is ignored
+      _ if self.disconnected.is_some() => (),
+      AnyPacket::Hello(hello) if self.features.is_none() => self.handle_hello(hello),
+      // Clients too old to send a hello won't understand anything we send
+      // them, but rejecting them at least shows the reason in the server log
+      _ if self.features.is_none() => {
+        self.reject("expected a hello packet - the client is probably outdated");
+      }
+      AnyPacket::Hello(_) => println!("Ignoring repeated hello packet from client {}", self.id),
+      // Registering again would reissue the session token and unbind UDP,
+      // even mid-match
+      AnyPacket::Reg(_) if self.is_registered() || self.registering => {
+        self.reject_registration("already registered");
+      }
+      AnyPacket::Reg(reg_packet) => {
         println!("Received reg packet with name \"{}\"", reg_packet.name);
+        self.registering = true;
+        self.server_packets.push(AnyPacket::Reg(reg_packet));
+      }
+      AnyPacket::GameJoin(_) | AnyPacket::GameListRequest(_) | AnyPacket::Ready(_) |
+      AnyPacket::RoomSettings(_) | AnyPacket::ChatSend(_) | AnyPacket::Input(_)
+          if !self.is_registered() => {
+        println!("Ignoring {} packet from unregistered client {}",
+                 String::from_utf8_lossy(&packet.tag()), self.id);
+      }
+      packet @ AnyPacket::GameJoin(_) | packet @ AnyPacket::GameListRequest(_) |
+      packet @ AnyPacket::Ready(_) | packet @ AnyPacket::RoomSettings(_) |
+      packet @ AnyPacket::ChatSend(_) | packet @ AnyPacket::Input(_) => {
+        self.server_packets.push(packet);
+      }
+      AnyPacket::SnapshotAck(ack) => self.ack_snapshot(ack.tick),
+      // Receiving anything counts as a heartbeat, so there's nothing more to do
+      AnyPacket::Pong(_) => (),
+      other => {
+        println!("Ignoring unexpected {} packet from client {}",
+                 String::from_utf8_lossy(&other.tag()), self.id);
       }
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::net::{self, TcpListener};
+  use common::net::RegPacket;
+  use config::DEFAULT_SEND_QUEUE_LIMIT;
+  use super::*;
+
+  /// A client connected over loopback, and the stream at the client's end.
+  fn connect(now: Instant) -> (Client, net::TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let client = Client::new(1 << 16, "", TcpStream::from_stream(stream).unwrap(), now);
+    (client, peer)
+  }
+
+  #[test]
+  fn second_registration_in_a_batch_is_rejected() {
+    let (mut client, _peer) = connect(Instant::now());
+    client.features = Some(0);
+    client.handle_packet(AnyPacket::Reg(RegPacket::new("alice")));
+    client.handle_packet(AnyPacket::Reg(RegPacket::new("bob")));
+    assert_eq!(client.server_packets.len(), 1);
+    assert!(client.registering);
+
+    client.accept_registration("alice".to_owned());
+    assert!(!client.registering);
+    client.handle_packet(AnyPacket::Reg(RegPacket::new("bob")));
+    assert_eq!(client.server_packets.len(), 1);
+  }
+
+  #[test]
+  fn send_queue_past_the_limit_disconnects() {
+    let (mut client, _peer) = connect(Instant::now());
+    client.tcp_out = vec![0; DEFAULT_SEND_QUEUE_LIMIT];
+    client.check_send_queue(DEFAULT_SEND_QUEUE_LIMIT);
+    assert_eq!(client.disconnected, None);
+
+    client.udp_out.push_back(vec![0; 1]);
+    client.check_send_queue(DEFAULT_SEND_QUEUE_LIMIT);
+    assert_eq!(client.disconnected, Some("not keeping up with the server".to_owned()));
+  }
+
+  #[test]
+  fn partial_flush_keeps_the_rest_in_order() {
+    let (mut client, mut peer) = connect(Instant::now());
+    peer.set_nonblocking(true).unwrap();
+    // Far more than the socket buffers hold, so the first flush can't write
+    // it all
+    let sent : Vec<u8> = (0..16 * 1024 * 1024).map(|i: u32| (i % 251) as u8).collect();
+    client.tcp_out = sent.clone();
+    client.flush_tcp().unwrap();
+    assert!(!client.tcp_out.is_empty() && client.tcp_out.len() < sent.len());
+    assert_eq!(client.tcp_out[..], sent[sent.len() - client.tcp_out.len()..]);
+
+    let mut received = Vec::with_capacity(sent.len());
+    let mut buf = [0; 64 * 1024];
+    while received.len() < sent.len() {
+      match peer.read(&mut buf) {
+        Ok(len) => received.extend_from_slice(&buf[..len]),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => client.flush_tcp().unwrap(),
+        Err(e) => panic!("{}", e),
+      }
+    }
+    assert!(client.tcp_out.is_empty());
+    assert!(received == sent);
+  }
+
+  #[test]
+  fn handshake_must_finish_in_time() {
+    let start = Instant::now();
+    let config = Config::default();
+    let (mut client, _peer) = connect(start);
+    client.name = "alice".to_owned();
+    client.check_timeouts(start + config.handshake_timeout, &config);
+    assert_eq!(client.disconnected, None);
+    // Registered, but without a UDP address
+    client.check_timeouts(start + config.handshake_timeout + Duration::from_millis(1), &config);
+    assert_eq!(client.disconnected, Some("handshake timed out".to_owned()));
+  }
+
+  #[test]
+  fn idle_client_times_out() {
+    let start = Instant::now();
+    let config = Config::default();
+    let (mut client, _peer) = connect(start);
+    client.name = "alice".to_owned();
+    client.bind_udp("127.0.0.1:5000".parse().unwrap());
+
+    let heard = start + config.idle_timeout;
+    client.last_heard = heard;
+    client.check_timeouts(heard + config.idle_timeout, &config);
+    assert_eq!(client.disconnected, None);
+    client.check_timeouts(heard + config.idle_timeout + Duration::from_millis(1), &config);
+    assert_eq!(client.disconnected, Some("timed out".to_owned()));
+  }
+
+  #[test]
+  fn heartbeat_pings_once_per_interval() {
+    let start = Instant::now();
+    let interval = Config::default().heartbeat_interval;
+    let (mut client, _peer) = connect(start);
+    // Nothing can be sent before the UDP address is bound
+    client.heartbeat(start + interval, interval);
+    assert_eq!(client.ping_seq, 0);
+
+    client.bind_udp("127.0.0.1:5000".parse().unwrap());
+    client.heartbeat(start + interval - Duration::from_millis(1), interval);
+    assert_eq!(client.ping_seq, 0);
+    client.heartbeat(start + interval, interval);
+    assert_eq!((client.ping_seq, client.last_ping), (1, start + interval));
+    client.heartbeat(start + interval * 2 - Duration::from_millis(1), interval);
+    assert_eq!(client.ping_seq, 1);
+    client.heartbeat(start + interval * 2, interval);
+    assert_eq!(client.ping_seq, 2);
+
+    client.queue_datagrams(start + interval * 2);
+    // Decode what was sent, as the client would
+    let mut peer_conn = Connection::new();
+    let seqs : Vec<u32> = client.udp_out.iter()
+      .flat_map(|d| peer_conn.receive(d, start).unwrap())
+      .map(|(_, message)| AnyPacket::from_frame(&Frame::from_message(&message).unwrap()).unwrap())
+      .filter_map(|packet| match packet { AnyPacket::Ping(ping) => Some(ping.seq), _ => None })
+      .collect();
+    assert_eq!(seqs, vec![1, 2]);
+  }
+}
@@ -0,0 +1,258 @@
+//! Storage for the connected clients. Clients live in a slab of slots, and a
+//! client's ID packs its slot index with the slot's generation, so looking a
+//! client up by ID (and so by mio `Token`) is O(1). Each time a slot is freed
+//! its generation is bumped, so a stale ID or token can never reach the next
+//! client to use the slot.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::{Index, IndexMut};
+use mio::Token;
+use client::Client;
+
+/// The number of low bits of a client ID holding the slot index.
+pub const INDEX_BITS : u32 = 16;
+/// The most clients which can be connected at once.
+pub const MAX_CLIENTS : usize = 1 << INDEX_BITS;
+
+/// Generations start from 1, so every client ID is at least `MAX_CLIENTS`.
+/// This leaves the tokens below free for the listening sockets, and keeps IDs
+/// within the `u32` they're sent to clients as.
+const FIRST_GENERATION : u16 = 1;
+
+struct Slot {
+  generation: u16,
+  client: Option<Client>,
+}
+
+/// Every connected client, indexed by ID.
+pub struct ClientList {
+  slots: Vec<Slot>,
+  /// Indices of the empty slots.
+  free: Vec<usize>,
+  /// The client each bound UDP address belongs to.
+  by_udp_addr: HashMap<SocketAddr, usize>,
+  /// The client each issued session token belongs to.
+  by_session_token: HashMap<u64, usize>,
+}
+
+fn make_id(index: usize, generation: u16) -> usize {
+  (generation as usize) << INDEX_BITS | index
+}
+
+fn split_id(id: usize) -> (usize, u16) {
+  (id & (MAX_CLIENTS - 1), (id >> INDEX_BITS) as u16)
+}
+
+impl ClientList {
+  pub fn new() -> ClientList {
+    ClientList {
+      slots: Vec::new(),
+      free: Vec::new(),
+      by_udp_addr: HashMap::new(),
+      by_session_token: HashMap::new(),
+    }
+  }
+
+  /// The token to register a client's TCP stream with.
+  pub fn token(id: usize) -> Token {
+    Token(id)
+  }
+
+  /// Add a client, reusing an empty slot if there is one.
+  /// # Params
+  /// * `new_client` - Creates the client given its new ID.
+  /// # Returns
+  /// The new client's ID, or `None` if the server is full.
+  pub fn insert<F: FnOnce(usize) -> Client>(&mut self, new_client: F) -> Option<usize> {
+    let index = match self.free.pop() {
+      Some(index) => index,
+      None if self.slots.len() < MAX_CLIENTS => {
+        self.slots.push(Slot { generation: FIRST_GENERATION, client: None });
+        self.slots.len() - 1
+      }
+      None => return None,
+    };
+    let slot = &mut self.slots[index];
+    let id = make_id(index, slot.generation);
+    slot.client = Some(new_client(id));
+    Some(id)
+  }
+
+  /// Remove a client, freeing its slot, UDP address and session token.
+  pub fn remove(&mut self, id: usize) -> Option<Client> {
+    let (index, generation) = split_id(id);
+    let slot = match self.slots.get_mut(index) {
+      Some(slot) if slot.generation == generation && slot.client.is_some() => slot,
+      _ => return None,
+    };
+    let client = slot.client.take().unwrap();
+    // Skip the generations which would give IDs clashing with other tokens
+    slot.generation = slot.generation.checked_add(1).unwrap_or(FIRST_GENERATION);
+    self.free.push(index);
+    if let Some(addr) = client.udp_addr {
+      if self.by_udp_addr.get(&addr) == Some(&id) { self.by_udp_addr.remove(&addr); }
+    }
+    if let Some(token) = client.session_token {
+      if self.by_session_token.get(&token) == Some(&id) { self.by_session_token.remove(&token); }
+    }
+    Some(client)
+  }
+
+  pub fn get(&self, id: usize) -> Option<&Client> {
+    let (index, generation) = split_id(id);
+    self.slots.get(index)
+      .filter(|slot| slot.generation == generation)
+      .and_then(|slot| slot.client.as_ref())
+  }
+
+  pub fn get_mut(&mut self, id: usize) -> Option<&mut Client> {
+    let (index, generation) = split_id(id);
+    self.slots.get_mut(index)
+      .filter(|slot| slot.generation == generation)
+      .and_then(|slot| slot.client.as_mut())
+  }
+
+  /// Accept a client's registration, indexing the session token it's issued
+  /// so the client's UDP hello can be matched to it.
+  pub fn accept_registration(&mut self, id: usize, name: String) {
+    let (old_token, token) = match self.get_mut(id) {
+      Some(c) => {
+        let old_token = c.session_token;
+        c.accept_registration(name);
+        (old_token, c.session_token)
+      }
+      None => return,
+    };
+    if let Some(old_token) = old_token.filter(|&old| Some(old) != token) {
+      self.by_session_token.remove(&old_token);
+    }
+    if let Some(token) = token { self.by_session_token.insert(token, id); }
+  }
+
+  /// Find the ID of the client issued a session token.
+  pub fn by_session_token(&self, token: u64) -> Option<usize> {
+    self.by_session_token.get(&token).cloned()
+  }
+
+  /// Bind a UDP address to a client, replacing any it had before.
+  pub fn bind_udp(&mut self, id: usize, addr: SocketAddr) {
+    let old_addr = match self.get_mut(id) {
+      Some(c) => {
+        let old_addr = c.udp_addr;
+        c.bind_udp(addr);
+        old_addr
+      }
+      None => return,
+    };
+    if let Some(old_addr) = old_addr {
+      if self.by_udp_addr.get(&old_addr) == Some(&id) { self.by_udp_addr.remove(&old_addr); }
+    }
+    // Another client can't still be using the address, so unbind it
+    if let Some(other) = self.by_udp_addr.insert(addr, id).filter(|&other| other != id) {
+      if let Some(c) = self.get_mut(other) { c.udp_addr = None; }
+    }
+  }
+
+  /// Find the client bound to a UDP address.
+  pub fn by_udp_addr_mut(&mut self, addr: SocketAddr) -> Option<&mut Client> {
+    let id = *self.by_udp_addr.get(&addr)?;
+    // The client may have unbound the address since, by registering again
+    self.get_mut(id).filter(|c| c.udp_addr == Some(addr))
+  }
+
+  /// The IDs of every client, so clients can be borrowed one at a time.
+  pub fn ids(&self) -> Vec<usize> {
+    self.iter().map(|c| c.id).collect()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Client> {
+    self.slots.iter().filter_map(|slot| slot.client.as_ref())
+  }
+
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Client> {
+    self.slots.iter_mut().filter_map(|slot| slot.client.as_mut())
+  }
+
+  /// Remove every client for which `f` returns false.
+  pub fn retain<F: FnMut(&mut Client) -> bool>(&mut self, mut f: F) {
+    for id in self.ids() {
+      if !f(self.get_mut(id).unwrap()) { self.remove(id); }
+    }
+  }
+}
+
+impl Default for ClientList {
+  fn default() -> ClientList {
+    ClientList::new()
+  }
+}
+
+/// Panics if there's no client with the ID.
+impl Index<usize> for ClientList {
+  type Output = Client;
+  fn index(&self, id: usize) -> &Client {
+    self.get(id).expect("no client with this ID")
+  }
+}
+
+impl IndexMut<usize> for ClientList {
+  fn index_mut(&mut self, id: usize) -> &mut Client {
+    self.get_mut(id).expect("no client with this ID")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{self, TcpListener};
+  use std::time::Instant;
+  use mio::net::TcpStream;
+  use super::*;
+
+  fn insert(list: &mut ClientList, listener: &TcpListener) -> usize {
+    let stream = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let stream = TcpStream::from_stream(stream).unwrap();
+    list.insert(|id| Client::new(id, "", stream, Instant::now())).unwrap()
+  }
+
+  #[test]
+  fn stale_id_misses_reused_slot() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut list = ClientList::new();
+    let old_id = insert(&mut list, &listener);
+    assert!(list.remove(old_id).is_some());
+    let new_id = insert(&mut list, &listener);
+    assert_eq!(split_id(new_id).0, split_id(old_id).0, "slot should be reused");
+    assert_ne!(new_id, old_id);
+    assert!(list.get(old_id).is_none());
+    assert!(list.get_mut(old_id).is_none());
+    assert!(list.remove(old_id).is_none());
+    assert_eq!(list[new_id].id, new_id);
+  }
+
+  #[test]
+  fn session_token_finds_client_until_removed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut list = ClientList::new();
+    let id = insert(&mut list, &listener);
+    list.accept_registration(id, "alice".to_owned());
+    let token = list[id].session_token.unwrap();
+    assert_eq!(list.by_session_token(token), Some(id));
+    list.remove(id);
+    assert_eq!(list.by_session_token(token), None);
+  }
+
+  #[test]
+  fn registering_again_retires_the_old_token() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut list = ClientList::new();
+    let id = insert(&mut list, &listener);
+    list.accept_registration(id, "alice".to_owned());
+    let old_token = list[id].session_token.unwrap();
+    list.accept_registration(id, "bob".to_owned());
+    let token = list[id].session_token.unwrap();
+    assert_ne!(token, old_token);
+    assert_eq!(list.by_session_token(old_token), None);
+    assert_eq!(list.by_session_token(token), Some(id));
+  }
+}
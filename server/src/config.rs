@@ -0,0 +1,71 @@
+//! Server settings which can be changed without rebuilding, read from
+//! environment variables.
+
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+use history::DEFAULT_MAX_REWIND_TICKS;
+
+/// How long a client can go without being heard from before it's dropped.
+pub const DEFAULT_IDLE_TIMEOUT_MS : u64 = 10_000;
+/// How long a client has to register and bind its UDP address.
+pub const DEFAULT_HANDSHAKE_TIMEOUT_MS : u64 = 5_000;
+/// How often clients are pinged.
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS : u64 = 1_000;
+/// How many bytes can be waiting to be sent to a client before it's dropped
+/// for not keeping up.
+pub const DEFAULT_SEND_QUEUE_LIMIT : usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+  /// Set with `SERVER_IDLE_TIMEOUT_MS`.
+  pub idle_timeout: Duration,
+  /// Set with `SERVER_HANDSHAKE_TIMEOUT_MS`.
+  pub handshake_timeout: Duration,
+  /// Set with `SERVER_HEARTBEAT_INTERVAL_MS`.
+  pub heartbeat_interval: Duration,
+  /// Set with `SERVER_SEND_QUEUE_LIMIT`, in bytes.
+  pub send_queue_limit: usize,
+  /// Set with `SERVER_MAX_REWIND_TICKS`.
+  pub max_rewind_ticks: u32,
+}
+
+impl Config {
+  /// Read the config from the environment, using the defaults for anything
+  /// unset or invalid.
+  pub fn from_env() -> Config {
+    Config {
+      idle_timeout: ms_from_env("SERVER_IDLE_TIMEOUT_MS", DEFAULT_IDLE_TIMEOUT_MS),
+      handshake_timeout: ms_from_env("SERVER_HANDSHAKE_TIMEOUT_MS", DEFAULT_HANDSHAKE_TIMEOUT_MS),
+      heartbeat_interval: ms_from_env("SERVER_HEARTBEAT_INTERVAL_MS", DEFAULT_HEARTBEAT_INTERVAL_MS),
+      send_queue_limit: from_env("SERVER_SEND_QUEUE_LIMIT", DEFAULT_SEND_QUEUE_LIMIT),
+      max_rewind_ticks: from_env("SERVER_MAX_REWIND_TICKS", DEFAULT_MAX_REWIND_TICKS),
+    }
+  }
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      idle_timeout: Duration::from_millis(DEFAULT_IDLE_TIMEOUT_MS),
+      handshake_timeout: Duration::from_millis(DEFAULT_HANDSHAKE_TIMEOUT_MS),
+      heartbeat_interval: Duration::from_millis(DEFAULT_HEARTBEAT_INTERVAL_MS),
+      send_queue_limit: DEFAULT_SEND_QUEUE_LIMIT,
+      max_rewind_ticks: DEFAULT_MAX_REWIND_TICKS,
+    }
+  }
+}
+
+fn from_env<T: FromStr>(name: &str, default: T) -> T {
+  match env::var(name) {
+    Ok(value) => value.parse().unwrap_or_else(|_| {
+      println!("Ignoring invalid {}: {:?}", name, value);
+      default
+    }),
+    Err(_) => default,
+  }
+}
+
+fn ms_from_env(name: &str, default: u64) -> Duration {
+  Duration::from_millis(from_env(name, default))
+}
@@ -0,0 +1,102 @@
+//! The recent history of a room's world, so it can be rewound when a late
+//! input arrives. Clients stamp their inputs with the tick they were made on,
+//! which by the time they reach the server has usually already been
+//! simulated. The world is rewound to that tick, the input inserted, and the
+//! world resimulated with every player's recorded inputs (see
+//! netcode_notes.txt).
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use common::net::InputPacket;
+use room::Entity;
+
+/// The most ticks the world is rewound, unless configured otherwise. At 60
+/// ticks per second, clients with pings over 200ms will be corrected.
+pub const DEFAULT_MAX_REWIND_TICKS : u32 = 6;
+/// The most inputs remembered for a player, so a client can't use up memory
+/// by sending inputs stamped far in the future.
+pub const MAX_TIMELINE_LEN : usize = 256;
+
+/// World states and player inputs for the last few ticks.
+pub struct History {
+  max_rewind: u32,
+  /// The world after each recent tick, oldest first.
+  states: VecDeque<(u32, Vec<Entity>)>,
+  /// Each player's inputs, by the tick they take effect. An input stays in
+  /// effect until the player's next input.
+  inputs: HashMap<usize, BTreeMap<u32, InputPacket>>,
+}
+
+impl History {
+  /// Create a history starting from the world at a tick.
+  /// # Params
+  /// * `max_rewind` - The most ticks the world can be resimulated.
+  /// * `tick` - The tick the world is at.
+  /// * `entities` - The world.
+  pub fn new(max_rewind: u32, tick: u32, entities: &[Entity]) -> History {
+    let mut history = History {
+      max_rewind: max_rewind.max(1),
+      states: VecDeque::with_capacity(max_rewind as usize + 1),
+      inputs: HashMap::new(),
+    };
+    history.record(tick, entities);
+    history
+  }
+
+  /// The earliest tick which can be resimulated.
+  pub fn earliest_tick(&self) -> u32 {
+    self.states.front().map_or(0, |&(tick, _)| tick + 1)
+  }
+
+  /// Record the world after a tick was simulated. States from this tick on
+  /// are replaced, as they're being resimulated.
+  pub fn record(&mut self, tick: u32, entities: &[Entity]) {
+    while self.states.back().is_some_and(|&(t, _)| t >= tick) { self.states.pop_back(); }
+    self.states.push_back((tick, entities.to_vec()));
+    while self.states.len() > self.max_rewind as usize + 1 { self.states.pop_front(); }
+
+    // Inputs are only needed from the oldest state on, plus the one in effect
+    // at that point
+    let oldest = self.states.front().unwrap().0;
+    for timeline in self.inputs.values_mut() {
+      while timeline.range(..=oldest).nth(1).is_some() {
+        let first = *timeline.keys().next().unwrap();
+        timeline.remove(&first);
+      }
+    }
+  }
+
+  /// The world after a tick, if it's still remembered.
+  pub fn state(&self, tick: u32) -> Option<&[Entity]> {
+    self.states.iter().find(|&&(t, _)| t == tick).map(|(_, entities)| &entities[..])
+  }
+
+  /// Record a player's input, replacing any input already taking effect on
+  /// the same tick.
+  /// # Params
+  /// * `client_id` - The player the input is from.
+  /// * `tick` - The tick the input takes effect on. This must have already
+  ///   been clamped to `earliest_tick`.
+  /// * `input` - The input.
+  pub fn add_input(&mut self, client_id: usize, tick: u32, input: InputPacket) {
+    let timeline = self.inputs.entry(client_id).or_default();
+    timeline.insert(tick, input);
+    while timeline.len() > MAX_TIMELINE_LEN {
+      let last = *timeline.keys().next_back().unwrap();
+      timeline.remove(&last);
+    }
+  }
+
+  /// The input a player has in effect on a tick.
+  pub fn input_at(&self, client_id: usize, tick: u32) -> Option<&InputPacket> {
+    self.inputs.get(&client_id)?.range(..=tick).next_back().map(|(_, input)| input)
+  }
+
+  /// Forget a player's inputs and entities, once they've left, so rewinding
+  /// doesn't bring them back.
+  pub fn remove_player(&mut self, client_id: usize) {
+    self.inputs.remove(&client_id);
+    for (_, entities) in self.states.iter_mut() {
+      entities.retain(|e| e.owner != Some(client_id));
+    }
+  }
+}
@@ -1,15 +1,171 @@
 extern crate mio;
+extern crate rand;
 extern crate common;
 
 mod client;
+mod clients;
+mod names;
+mod chat;
+mod config;
+mod broadcast;
+mod room;
+mod tick;
+mod history;
 
 use client::Client;
+use clients::ClientList;
+use room::{Room, Rooms};
+use config::Config;
+use broadcast::{send_to, broadcast_room, broadcast_lobby, broadcast_snapshot};
+use chat::{ProfanityFilter, WordFilter, NoFilter, CHAT_FILTER_PATH};
 use mio::net::{UdpSocket, TcpListener};
 use mio::{Token, Poll, Ready, PollOpt, Events};
+use common::net::{MAX_DATAGRAM_SIZE, AnyPacket, Frame, GameJoinAckPacket, GameJoinRejectPacket,
+                  LobbyRejectPacket, MatchStartPacket, ChatScope, ChatBroadcastPacket,
+                  ChatRejectPacket, PlayerLeftPacket};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How often the main loop wakes up to resend datagrams, send heartbeats and
+/// check timeouts when nothing else is happening. While a match is running,
+/// the loop also wakes up for each game tick.
+const POLL_TIMEOUT_MS : u64 = 50;
+/// The size of the buffer TCP streams are read into.
+const READ_BUF_SIZE : usize = 16 * 1024;
+
+/// Tell a room's players its lobby has changed. The match starts if this
+/// leaves every player ready, such as when the last player who wasn't ready
+/// leaves.
+fn lobby_changed(client_list: &mut ClientList, room: &mut Room, now: Instant) {
+  let started = room.try_start(now);
+  broadcast_lobby(client_list, room);
+  if started {
+    let start = MatchStartPacket { room_id: room.id, settings: room.settings.clone() };
+    broadcast_room(client_list, room, &start);
+  }
+}
+
+/// Handle a packet which needs more than the client that sent it.
+/// # Params
+/// * `client_list` - Every client.
+/// * `client_id` - The ID of the client which sent the packet.
+/// * `rooms` - Every room.
+/// * `chat_filter` - The filter applied to chat messages.
+/// * `packet` - A packet from the client's `server_packets`.
+/// * `now` - The current time.
+fn handle_server_packet(client_list: &mut ClientList, client_id: usize, rooms: &mut Rooms,
+                        chat_filter: &dyn ProfanityFilter, packet: AnyPacket, now: Instant)
+    -> io::Result<()> {
+  match packet {
+    AnyPacket::Reg(reg_packet) => {
+      let taken = client_list.iter().filter(|c| c.id != client_id).map(|c| &c.name[..]);
+      match names::validate_name(&reg_packet.name, taken) {
+        Ok(()) => client_list.accept_registration(client_id, reg_packet.name),
+        Err(e) => {
+          let c = &mut client_list[client_id];
+          c.registering = false;
+          c.reject_registration(&e.to_string());
+        }
+      }
+      Ok(())
+    }
+    AnyPacket::GameJoin(join) => {
+      let previous = client_list[client_id].room;
+      match rooms.join(client_id, previous, join.room_id) {
+        Ok(room_id) => {
+          client_list[client_id].room = Some(room_id);
+          client_list[client_id].send_tcp(&GameJoinAckPacket { room_id })?;
+          if let Some(room) = previous.and_then(|id| rooms.get_mut(id)) { lobby_changed(client_list, room, now); }
+          broadcast_lobby(client_list, rooms.get(room_id).unwrap());
+          Ok(())
+        }
+        Err(reason) => client_list[client_id].send_tcp(&GameJoinRejectPacket { reason: reason.to_owned() }),
+      }
+    }
+    AnyPacket::GameListRequest(_) => client_list[client_id].send_tcp(&rooms.list()),
+    AnyPacket::Ready(_) | AnyPacket::RoomSettings(_) => {
+      let room = match client_list[client_id].room.and_then(|id| rooms.get_mut(id)) {
+        Some(room) => room,
+        None => return client_list[client_id].send_tcp(&LobbyRejectPacket {
+          reason: "you aren't in a room".to_owned() }),
+      };
+      let result = match packet {
+        AnyPacket::Ready(ready) => room.set_ready(client_id, ready.ready),
+        AnyPacket::RoomSettings(settings) => room.change_settings(client_id, settings.settings),
+        _ => unreachable!(),
+      };
+      if let Err(reason) = result {
+        return client_list[client_id].send_tcp(&LobbyRejectPacket { reason: reason.to_owned() });
+      }
+      lobby_changed(client_list, room, now);
+      Ok(())
+    }
+    AnyPacket::ChatSend(chat) => {
+      let reject = |c: &mut Client, reason: &str| {
+        c.send_tcp(&ChatRejectPacket { reason: reason.to_owned() })
+      };
+      if !client_list[client_id].chat_limiter.try_send(now) {
+        return reject(&mut client_list[client_id], "you're sending messages too quickly");
+      }
+      let text = match chat::clean_message(&chat.text) {
+        Ok(text) => chat_filter.filter(&text),
+        Err(reason) => return reject(&mut client_list[client_id], reason),
+      };
+
+      // Find who the message is for
+      let recipients = match chat.scope {
+        ChatScope::Room | ChatScope::Team => {
+          let room = match client_list[client_id].room.and_then(|id| rooms.get(id)) {
+            Some(room) => room,
+            None => return reject(&mut client_list[client_id], "you aren't in a room"),
+          };
+          let team = room.team_of(client_id);
+          room.players.iter().cloned()
+            .filter(|&id| chat.scope == ChatScope::Room || room.team_of(id) == team)
+            .collect()
+        }
+        ChatScope::Whisper(target) => {
+          let target = target as usize;
+          if !client_list.get(target).is_some_and(|c| c.is_registered()) {
+            return reject(&mut client_list[client_id], "there's no player with that ID");
+          }
+          vec![client_id, target]
+        }
+      };
+      let broadcast = ChatBroadcastPacket {
+        sender_id: client_id as u32,
+        sender_name: client_list[client_id].name.clone(),
+        scope: chat.scope,
+        text,
+      };
+      send_to(client_list, &recipients, &broadcast);
+      Ok(())
+    }
+    AnyPacket::Input(input) => {
+      if let Some(room) = client_list[client_id].room.and_then(|id| rooms.get_mut(id)) {
+        room.receive_input(client_id, input);
+      }
+      Ok(())
+    }
+    _ => Ok(()),
+  }
+}
 
 fn main() {
-  // A list of clients.
-  let mut client_list : Vec<Client> = Vec::new();
+  let config = Config::from_env();
+
+  // The connected clients.
+  let mut client_list = ClientList::new();
+  // The games being hosted.
+  let mut rooms = Rooms::new(config.max_rewind_ticks);
+  // The filter applied to chat messages
+  let chat_filter : Box<dyn ProfanityFilter> = match WordFilter::from_file(CHAT_FILTER_PATH) {
+    Ok(filter) => Box::new(filter),
+    Err(e) => {
+      println!("Not filtering chat, couldn't read {}: {}", CHAT_FILTER_PATH, e);
+      Box::new(NoFilter)
+    }
+  };
 
   // Set up a token to identify the UDP socket can be read.
   const TCP: Token = Token(0);
@@ -27,53 +183,168 @@ fn main() {
 
   // Start listening for incoming connections
   poll.register(&tcp_server, TCP, Ready::readable(), PollOpt::edge()).unwrap();
-  poll.register(&udp_server, UDP, Ready::readable(), PollOpt::edge()).unwrap();
+  poll.register(&udp_server, UDP, Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap();
 
   // Create storage for events
   let mut events = Events::with_capacity(1024);
 
-  let mut curr_client_id = 2;
+  // Buffers to receive UDP datagrams and TCP data into
+  let mut datagram_buf = [0; MAX_DATAGRAM_SIZE];
+  let mut read_buf = vec![0; READ_BUF_SIZE];
 
   loop {
-    poll.poll(&mut events, None).unwrap();
+    // Sleep until there's something to do, or the next tick is due
+    let timeout = Duration::from_millis(POLL_TIMEOUT_MS);
+    let timeout = rooms.time_until_tick(Instant::now()).map_or(timeout, |t| t.min(timeout));
+    poll.poll(&mut events, Some(timeout)).unwrap();
+    let now = Instant::now();
 
     for event in events.iter() {
       match event.token() {
-        TCP => { 
-          // Accept a socket connection, and add it to the list of clients.
-          let stream = tcp_server.accept().unwrap().0;
-          let id = curr_client_id;
-          curr_client_id += 1;
-          client_list.push(Client::new(id, "", stream));
+        TCP => loop {
+          // Accept every waiting connection, and add each to the list of
+          // clients.
+          let stream = match tcp_server.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => { println!("Failed to accept connection: {}", e); break; }
+          };
+          let id = match client_list.insert(|id| Client::new(id, "", stream, now)) {
+            Some(id) => id,
+            None => { println!("Refusing connection, the server is full"); continue; }
+          };
 
           // Register poll to listen for this new TCP stream
-          let c = client_list.last().unwrap();
-          poll.register(&c.tcp_stream, Token(id), Ready::readable(), PollOpt::edge()).unwrap();
-        }
-        UDP => { // Received a UDP message
-          println!("Received a UDP datagram"); 
-        }
-        Token(x) => { // Received a TCP message from client with ID x
-          // Find the client this refers to
-          let mut client = None;
-          for c in &mut client_list {
-            if c.id == x { client = Some(c); break; }
+          if let Err(e) = poll.register(&client_list[id].tcp_stream, ClientList::token(id),
+                                        Ready::readable() | Ready::writable(), PollOpt::edge()) {
+            println!("Failed to register client {}: {}", id, e);
+            client_list.remove(id);
+          }
+        },
+        UDP => {
+          // Received UDP messages, read until there are none left
+          while event.readiness().is_readable() {
+            let (len, addr) = match udp_server.recv_from(&mut datagram_buf) {
+              Ok(res) => res,
+              Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+              Err(e) => { println!("Failed to receive UDP datagram: {}", e); break; }
+            };
+            let datagram = &datagram_buf[..len];
+
+            // A hello binds the sender's address to the client with the
+            // matching session token, and is echoed back so the client knows it
+            // arrived
+            let hello = Frame::from_message(datagram).and_then(|f| AnyPacket::from_frame(&f));
+            if let Ok(AnyPacket::UdpHello(hello)) = hello {
+              match client_list.by_session_token(hello.session_token) {
+                Some(id) => {
+                  client_list.bind_udp(id, addr);
+                  client_list[id].udp_out.push_back(datagram.to_vec());
+                }
+                None => println!("Ignoring UDP hello with unknown session token from {}", addr),
+              }
+              continue;
+            }
+
+            match client_list.by_udp_addr_mut(addr) {
+              Some(c) => c.receive_datagram(datagram, now),
+              None => println!("Dropping unauthenticated UDP datagram from {}", addr),
+            }
           }
-          if client.is_none() { continue; }
-          let client = client.unwrap();
+
+          // Send datagrams which didn't fit in the socket before
+          if event.readiness().is_writable() {
+            for c in client_list.iter_mut() { c.flush_udp(&udp_server); }
+          }
+        }
+        Token(x) => { // A TCP stream from client with ID x is ready
+          // Find the client this refers to. The client may have been dropped
+          // since the event was queued.
+          let client = match client_list.get_mut(x) {
+            Some(client) => client,
+            None => continue,
+          };
 
           // Read messages from this TCP stream, and add to the tcp data queue
           // for this client
-          use std::io::Read;
-          let mut buf = Vec::new();
-          client.tcp_stream.read_to_end(&mut buf).unwrap();
-          client.tcp_buf.extend(buf.iter());
+          if event.readiness().is_readable() { client.read_tcp(&mut read_buf, now); }
+
+          // Write anything which didn't fit in the stream before
+          if event.readiness().is_writable() {
+            if let Err(e) = client.flush_tcp() {
+              client.disconnected = Some(format!("connection error: {}", e));
+            }
+          }
+        }
+      }
+    }
+
+    for c in client_list.iter_mut() { c.check_timeouts(now, &config); }
+
+    // Parse any received packets, then drop clients which are disconnecting,
+    // sent bad data or timed out
+    let mut left = Vec::new();
+    client_list.retain(|c| {
+      if let Err(e) = c.try_parse_packets() {
+        c.disconnect(&format!("sent malformed data: {}", e));
+      }
+      let reason = match c.disconnected {
+        Some(ref reason) => reason.clone(),
+        None => return true,
+      };
+      println!("Dropping client {}: {}", c.id, reason);
+      // Make a last attempt to send anything queued, such as why it was dropped
+      let _ = c.flush_tcp();
+      if let Err(e) = poll.deregister(&c.tcp_stream) {
+        println!("Failed to deregister client {}: {}", c.id, e);
+      }
+      if let Some(room_id) = c.room {
+        if rooms.leave(c.id, room_id).is_some() {
+          left.push((room_id, PlayerLeftPacket { client_id: c.id as u32, name: c.name.clone(), reason }));
         }
       }
+      false
+    });
+
+    // Tell the players left behind, who may have a new host
+    for (room_id, player_left) in left {
+      if let Some(room) = rooms.get_mut(room_id) {
+        broadcast_room(&mut client_list, room, &player_left);
+        lobby_changed(&mut client_list, room, now);
+      }
     }
 
-    for c in &mut client_list {
-      c.try_parse_packets();
+    // Handle packets which need the other clients or the rooms
+    for id in client_list.ids() {
+      for packet in ::std::mem::take(&mut client_list[id].server_packets) {
+        if let Err(e) = handle_server_packet(&mut client_list, id, &mut rooms, &*chat_filter,
+                                                packet, now) {
+          println!("Failed to reply to client {}: {}", id, e);
+        }
+      }
+    }
+
+    // Simulate every running match, sending snapshots on comm ticks. Rooms
+    // which fell behind run several ticks to catch up.
+    for room in rooms.iter_mut() {
+      for _ in 0..room.due_ticks(now) {
+        room.step();
+        if room.is_comm_tick() { broadcast_snapshot(&mut client_list, room, now); }
+      }
+    }
+
+    // Send any heartbeats, then as much of each client's queued data as the
+    // sockets will take. Whatever's left is sent once they're writable again,
+    // unless the client has fallen too far behind.
+    for c in client_list.iter_mut() {
+      c.heartbeat(now, config.heartbeat_interval);
+      c.queue_datagrams(now);
+      c.flush_udp(&udp_server);
+      if let Err(e) = c.flush_tcp() {
+        c.disconnected = Some(format!("connection error: {}", e));
+      } else {
+        c.check_send_queue(config.send_queue_limit);
+      }
     }
   }
 }
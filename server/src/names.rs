@@ -0,0 +1,105 @@
+//! Rules for the names clients register with.
+
+use std::fmt;
+
+/// The shortest name a client can register with.
+pub const MIN_NAME_LEN : usize = 3;
+/// The longest name a client can register with.
+pub const MAX_NAME_LEN : usize = 16;
+
+/// Names which could be mistaken for messages from the server. Compared
+/// ignoring case.
+const RESERVED_NAMES : &[&str] = &["server", "admin", "administrator", "moderator", "system",
+                                   "console", "host"];
+
+/// Why a name was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NameError {
+  TooShort,
+  TooLong,
+  /// The name contained a character other than an ASCII letter, digit,
+  /// underscore or hyphen.
+  InvalidChar(char),
+  Reserved,
+  /// Another client is already registered with the name.
+  Taken,
+}
+
+impl fmt::Display for NameError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      NameError::TooShort => write!(f, "names must be at least {} characters", MIN_NAME_LEN),
+      NameError::TooLong => write!(f, "names must be at most {} characters", MAX_NAME_LEN),
+      NameError::InvalidChar(c) =>
+        write!(f, "names may only contain letters, digits, '_' and '-', not {:?}", c),
+      NameError::Reserved => write!(f, "that name is reserved"),
+      NameError::Taken => write!(f, "that name is already taken"),
+    }
+  }
+}
+
+/// Check a name is allowed to be registered.
+/// # Params
+/// * `name` - The requested name.
+/// * `taken` - The names of other registered clients. Names are unique
+///   ignoring case, so 'john' and 'John' can't both register.
+pub fn validate_name<'a, I>(name: &str, taken: I) -> Result<(), NameError>
+    where I: IntoIterator<Item = &'a str> {
+  // Only ASCII is allowed, so the byte length is the character count
+  if let Some(c) = name.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')) {
+    return Err(NameError::InvalidChar(c));
+  }
+  if name.len() < MIN_NAME_LEN { return Err(NameError::TooShort); }
+  if name.len() > MAX_NAME_LEN { return Err(NameError::TooLong); }
+  if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(name)) {
+    return Err(NameError::Reserved);
+  }
+  if taken.into_iter().any(|t| t.eq_ignore_ascii_case(name)) {
+    return Err(NameError::Taken);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const NONE : [&str; 0] = [];
+
+  #[test]
+  fn valid_names_are_accepted() {
+    for name in &["Bob", "john_smith", "x-_-x", "Player1234567890"] {
+      assert_eq!(validate_name(name, NONE), Ok(()), "{}", name);
+    }
+  }
+
+  #[test]
+  fn length_is_limited() {
+    assert_eq!(validate_name("ab", NONE), Err(NameError::TooShort));
+    assert_eq!(validate_name("", NONE), Err(NameError::TooShort));
+    assert_eq!(validate_name(&"a".repeat(MAX_NAME_LEN + 1), NONE), Err(NameError::TooLong));
+  }
+
+  #[test]
+  fn only_ascii_letters_digits_and_some_symbols_are_allowed() {
+    assert_eq!(validate_name("john smith", NONE), Err(NameError::InvalidChar(' ')));
+    assert_eq!(validate_name("jöhn", NONE), Err(NameError::InvalidChar('ö')));
+    // Caught before the length, which only counts ASCII correctly
+    assert_eq!(validate_name("ab\u{1f600}", NONE), Err(NameError::InvalidChar('\u{1f600}')));
+  }
+
+  #[test]
+  fn reserved_names_are_rejected_ignoring_case() {
+    assert_eq!(validate_name("Server", NONE), Err(NameError::Reserved));
+    assert_eq!(validate_name("ADMIN", NONE), Err(NameError::Reserved));
+    assert_eq!(validate_name("servers", NONE), Ok(()));
+  }
+
+  #[test]
+  fn names_are_unique_ignoring_case() {
+    let taken = ["John", "alice"];
+    assert_eq!(validate_name("john", taken.iter().cloned()), Err(NameError::Taken));
+    assert_eq!(validate_name("ALICE", taken.iter().cloned()), Err(NameError::Taken));
+    assert_eq!(validate_name("Johnny", taken.iter().cloned()), Ok(()));
+  }
+}
@@ -0,0 +1,690 @@
+//! Game rooms. The server hosts many rooms at once, each with its own world,
+//! tick counter and players, so several matches can run on one server.
+//!
+//! A room starts in its lobby, where players mark themselves ready and the
+//! host (the longest standing player) can change the room's settings. The
+//! match starts once every player is ready.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tick::TickScheduler;
+use history::{History, DEFAULT_MAX_REWIND_TICKS};
+use common::physics::{self, Body, Object};
+use common::net::{InputPacket, InputAck, EntityState, SnapshotPacket, RoomInfo, RoomState, RoomSettings, GameListPacket,
+                  LobbyPlayer, LobbyStatePacket};
+
+/// The number of players a room holds, unless the host changes it.
+pub const DEFAULT_MAX_PLAYERS : usize = 8;
+/// The most players a host can allow into a room.
+pub const MAX_PLAYERS_LIMIT : usize = 32;
+/// The game tickrate, unless the host changes it.
+pub const DEFAULT_TICKRATE : u32 = 60;
+/// The slowest game tickrate a host can choose.
+pub const MIN_TICKRATE : u32 = 10;
+/// The fastest game tickrate a host can choose.
+pub const MAX_TICKRATE : u32 = 120;
+/// The map played, unless the host changes it.
+pub const DEFAULT_MAP : &str = "default";
+/// The longest map name a host can choose.
+pub const MAX_MAP_NAME_LEN : usize = 32;
+/// The number of teams players are split into.
+pub const NUM_TEAMS : u8 = 2;
+/// The colour of each team's players.
+pub const TEAM_COLORS : [[f32; 4]; NUM_TEAMS as usize] = [[1.0, 0.3, 0.3, 1.0], [0.3, 0.3, 1.0, 1.0]];
+/// The ground every map is played on, X, Y, W, H.
+pub const GROUND_AABB : [f32; 4] = [0.0, 500.0, 800.0, 100.0];
+/// The size of a player, W, H.
+pub const PLAYER_SIZE : [f32; 2] = [32.0, 32.0];
+/// The most rooms which can exist at once.
+pub const MAX_ROOMS : usize = 64;
+
+/// An entity in a room's world.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entity {
+  pub id: u32,
+  /// The client controlling this entity, if any.
+  pub owner: Option<usize>,
+  /// R, G, B, A
+  pub color: [f32; 4],
+  pub object: Object,
+}
+
+impl Entity {
+  /// The state of this entity to send to clients.
+  pub fn state(&self) -> EntityState {
+    EntityState {
+      id: self.id,
+      aabb: self.object.aabb,
+      vel: self.object.body.map_or([0.0, 0.0], |b| b.vel),
+      color: self.color,
+      grounded: self.object.body.is_some_and(|b| b.is_grounded()),
+    }
+  }
+}
+
+impl AsMut<Object> for Entity {
+  fn as_mut(&mut self) -> &mut Object {
+    &mut self.object
+  }
+}
+
+/// A single game, and the clients playing it.
+pub struct Room {
+  pub id: u32,
+  pub state: RoomState,
+  pub settings: RoomSettings,
+  /// The current game tick.
+  pub tick: u32,
+  /// The IDs of the clients in this room, in the order they joined.
+  pub players: Vec<usize>,
+  /// The client which can change the room's settings. Always in `players`.
+  pub host: usize,
+  /// The team of each player.
+  pub teams: HashMap<usize, u8>,
+  /// The players who are ready for the match to start.
+  pub ready: HashSet<usize>,
+  /// Every entity in this room's world, in the order they're simulated.
+  pub entities: Vec<Entity>,
+  /// Schedules the game ticks while the match is running.
+  pub scheduler: Option<TickScheduler>,
+  /// The world's recent history while the match is running, to rewind to
+  /// when a late input arrives.
+  pub history: Option<History>,
+  /// The most ticks the world is rewound for a late input.
+  pub max_rewind: u32,
+  /// The tick of the latest input received from each player.
+  pub last_input: HashMap<usize, u32>,
+}
+
+impl Room {
+  /// Create a room in its lobby, hosted by the client which caused it to be
+  /// created.
+  /// # Params
+  /// * `id` - The ID of the room.
+  /// * `host` - The client which caused the room to be created.
+  /// * `max_rewind` - The most ticks the world is rewound for a late input.
+  pub fn new(id: u32, host: usize, max_rewind: u32) -> Room {
+    Room {
+      id,
+      state: RoomState::Lobby,
+      settings: RoomSettings {
+        map: DEFAULT_MAP.to_owned(),
+        max_players: DEFAULT_MAX_PLAYERS as u32,
+        tickrate: DEFAULT_TICKRATE,
+      },
+      tick: 0,
+      players: Vec::new(),
+      host,
+      teams: HashMap::new(),
+      ready: HashSet::new(),
+      entities: Vec::new(),
+      scheduler: None,
+      history: None,
+      max_rewind,
+      last_input: HashMap::new(),
+    }
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.players.len() >= self.settings.max_players as usize
+  }
+
+  /// Take a snapshot of this room's world.
+  pub fn snapshot(&self) -> SnapshotPacket {
+    let mut acks : Vec<_> = self.last_input.iter()
+      .map(|(&client_id, &tick)| InputAck { client_id: client_id as u32, tick })
+      .collect();
+    acks.sort_by_key(|ack| ack.client_id);
+    SnapshotPacket {
+      tick: self.tick,
+      acks,
+      entities: self.entities.iter().map(Entity::state).collect(),
+    }
+  }
+
+  pub fn info(&self) -> RoomInfo {
+    RoomInfo {
+      id: self.id,
+      players: self.players.len() as u32,
+      max_players: self.settings.max_players,
+      state: self.state,
+    }
+  }
+
+  /// The team a player is on, if they're in this room.
+  pub fn team_of(&self, client_id: usize) -> Option<u8> {
+    self.teams.get(&client_id).cloned()
+  }
+
+  /// Mark a player as ready or not.
+  /// # Returns
+  /// An error if the match has already started.
+  pub fn set_ready(&mut self, client_id: usize, ready: bool) -> Result<(), &'static str> {
+    if self.state != RoomState::Lobby { return Err("the match has already started"); }
+    if ready { self.ready.insert(client_id); } else { self.ready.remove(&client_id); }
+    Ok(())
+  }
+
+  /// Change the room's settings.
+  /// # Returns
+  /// An error if the player isn't the host, the match has already started, or
+  /// the settings are invalid.
+  pub fn change_settings(&mut self, client_id: usize, settings: RoomSettings)
+      -> Result<(), &'static str> {
+    if client_id != self.host { return Err("only the host can change the room's settings"); }
+    if self.state != RoomState::Lobby { return Err("the match has already started"); }
+    if settings.map.is_empty() || settings.map.len() > MAX_MAP_NAME_LEN {
+      return Err("invalid map name");
+    }
+    let max_players = settings.max_players as usize;
+    if max_players == 0 || max_players > MAX_PLAYERS_LIMIT {
+      return Err("invalid max players");
+    }
+    if max_players < self.players.len() {
+      return Err("max players is less than the number of players in the room");
+    }
+    if settings.tickrate < MIN_TICKRATE || settings.tickrate > MAX_TICKRATE {
+      return Err("invalid tickrate");
+    }
+    // Players agreed to the old settings, so must ready up again
+    self.ready.clear();
+    self.settings = settings;
+    Ok(())
+  }
+
+  /// Start the match if every player is ready. The first tick is due
+  /// straight away.
+  /// # Returns
+  /// Whether the match was started.
+  pub fn try_start(&mut self, now: Instant) -> bool {
+    if self.state != RoomState::Lobby || self.players.is_empty() { return false; }
+    if !self.players.iter().all(|id| self.ready.contains(id)) { return false; }
+    println!("Starting match in room {} on map \"{}\"", self.id, self.settings.map);
+    self.state = RoomState::InGame;
+    self.tick = 0;
+    self.ready.clear();
+    self.spawn_world();
+    self.history = Some(History::new(self.max_rewind, self.tick, &self.entities));
+    self.last_input.clear();
+    self.scheduler = Some(TickScheduler::new(self.settings.tickrate, now));
+    true
+  }
+
+  /// Fill the world with the ground, and an entity for each player spread
+  /// out above it.
+  fn spawn_world(&mut self) {
+    self.entities.clear();
+    self.entities.push(Entity {
+      id: 0,
+      owner: None,
+      color: [0.5, 0.5, 0.5, 1.0],
+      object: Object { aabb: GROUND_AABB, body: None },
+    });
+    let spacing = GROUND_AABB[2] / (self.players.len() + 1) as f32;
+    for (i, &id) in self.players.iter().enumerate() {
+      let x = GROUND_AABB[0] + spacing * (i + 1) as f32 - PLAYER_SIZE[0] / 2.0;
+      let y = GROUND_AABB[1] - PLAYER_SIZE[1];
+      self.entities.push(Entity {
+        id: id as u32,
+        owner: Some(id),
+        color: TEAM_COLORS[self.teams[&id] as usize],
+        object: Object {
+          aabb: [x, y, PLAYER_SIZE[0], PLAYER_SIZE[1]],
+          body: Some(Body::player([0.0, 0.0])),
+        },
+      });
+    }
+  }
+
+  /// How long until the next tick is due, if the match is running.
+  pub fn time_until_tick(&self, now: Instant) -> Option<Duration> {
+    self.scheduler.as_ref().map(|s| s.time_until_tick(now))
+  }
+
+  /// Take the number of ticks due to be simulated by now.
+  pub fn due_ticks(&mut self, now: Instant) -> u32 {
+    self.scheduler.as_mut().map_or(0, |s| s.due_ticks(now))
+  }
+
+  /// Simulate the world for one tick.
+  pub fn step(&mut self) {
+    if self.scheduler.is_none() { return; }
+    self.tick += 1;
+    self.simulate_tick();
+  }
+
+  /// Simulate the current tick from the world after the tick before,
+  /// applying each player's input in effect on the tick.
+  fn simulate_tick(&mut self) {
+    let history = match (&self.scheduler, &mut self.history) {
+      (Some(_), Some(history)) => history,
+      _ => return,
+    };
+    for e in &mut self.entities {
+      let (owner, body) = match (e.owner, e.object.body.as_mut()) {
+        (Some(owner), Some(body)) => (owner, body),
+        _ => continue,
+      };
+      if let Some(input) = history.input_at(owner, self.tick) { physics::apply_input(body, input); }
+    }
+    physics::step(&mut self.entities, physics::tick_len(self.settings.tickrate));
+    history.record(self.tick, &self.entities);
+  }
+
+  /// Insert a player's input into the world's history. If the tick it was
+  /// made on has already been simulated, the world is rewound and
+  /// resimulated with it. Inputs older than the rewind limit take effect as
+  /// far back as the world can be rewound instead.
+  pub fn receive_input(&mut self, client_id: usize, input: InputPacket) {
+    if !self.players.contains(&client_id) { return; }
+    // Datagrams can arrive out of order, and an older input is out of date
+    if self.last_input.get(&client_id).is_some_and(|&tick| tick >= input.tick) { return; }
+    let tick = match self.history {
+      Some(ref mut history) => {
+        let tick = input.tick.max(history.earliest_tick());
+        history.add_input(client_id, tick, input);
+        tick
+      }
+      None => return,
+    };
+    self.last_input.insert(client_id, input.tick);
+    if tick <= self.tick { self.rewind_and_resimulate(tick); }
+  }
+
+  /// Rewind the world to before a tick, then simulate it back up to the
+  /// current tick with every player's recorded inputs.
+  /// # Params
+  /// * `from_tick` - The first tick to resimulate. Ticks older than the
+  ///   rewind limit allows are clamped to the earliest tick which can be
+  ///   resimulated.
+  pub fn rewind_and_resimulate(&mut self, from_tick: u32) {
+    let (from_tick, state) = match self.history {
+      Some(ref history) => {
+        let from_tick = from_tick.max(history.earliest_tick());
+        if from_tick > self.tick { return; }
+        (from_tick, history.state(from_tick - 1).unwrap().to_vec())
+      }
+      None => return,
+    };
+    let current_tick = self.tick;
+    self.entities = state;
+    for tick in from_tick..=current_tick {
+      self.tick = tick;
+      self.simulate_tick();
+    }
+  }
+
+  /// Whether a snapshot should be sent for the current tick.
+  pub fn is_comm_tick(&self) -> bool {
+    self.scheduler.as_ref().is_some_and(|s| s.is_comm_tick(self.tick))
+  }
+
+  /// Build the lobby state to send to the players.
+  /// # Params
+  /// * `name` - Looks up the name of a player from its client ID.
+  pub fn lobby_state<F: Fn(usize) -> String>(&self, name: F) -> LobbyStatePacket {
+    LobbyStatePacket {
+      room_id: self.id,
+      host: self.host as u32,
+      settings: self.settings.clone(),
+      players: self.players.iter().map(|&id| LobbyPlayer {
+        client_id: id as u32,
+        name: name(id),
+        team: self.teams[&id],
+        ready: self.ready.contains(&id),
+      }).collect(),
+    }
+  }
+
+  /// Add a player to the team with the fewest players.
+  fn add_player(&mut self, client_id: usize) {
+    let team = (0..NUM_TEAMS)
+      .min_by_key(|&t| self.teams.values().filter(|&&pt| pt == t).count())
+      .unwrap();
+    self.players.push(client_id);
+    self.teams.insert(client_id, team);
+  }
+
+  /// Remove a player, passing hosting on to the longest standing remaining
+  /// player if the host left.
+  fn remove_player(&mut self, client_id: usize) {
+    self.players.retain(|&id| id != client_id);
+    self.entities.retain(|e| e.owner != Some(client_id));
+    self.last_input.remove(&client_id);
+    if let Some(ref mut history) = self.history { history.remove_player(client_id); }
+    self.teams.remove(&client_id);
+    self.ready.remove(&client_id);
+    if self.host == client_id {
+      if let Some(&host) = self.players.first() {
+        println!("Client {} is now the host of room {}", host, self.id);
+        self.host = host;
+      }
+    }
+  }
+}
+
+/// Every room on the server.
+pub struct Rooms {
+  rooms: Vec<Room>,
+  next_id: u32,
+  /// The most ticks each room's world is rewound for a late input.
+  max_rewind: u32,
+}
+
+impl Rooms {
+  pub fn new(max_rewind: u32) -> Rooms {
+    Rooms { rooms: Vec::new(), next_id: 1, max_rewind }
+  }
+
+  pub fn get(&self, id: u32) -> Option<&Room> {
+    self.rooms.iter().find(|r| r.id == id)
+  }
+
+  pub fn get_mut(&mut self, id: u32) -> Option<&mut Room> {
+    self.rooms.iter_mut().find(|r| r.id == id)
+  }
+
+  pub fn iter_mut(&mut self) -> ::std::slice::IterMut<'_, Room> {
+    self.rooms.iter_mut()
+  }
+
+  /// How long until any running match is next due a tick.
+  pub fn time_until_tick(&self, now: Instant) -> Option<Duration> {
+    self.rooms.iter().filter_map(|r| r.time_until_tick(now)).min()
+  }
+
+  /// Move a client into a room, taking it out of the room it's in now. The
+  /// client only leaves its current room once the new one is known to have
+  /// space for it.
+  /// # Params
+  /// * `client_id` - The client joining.
+  /// * `current` - The room the client is in now, if any.
+  /// * `room_id` - The room to join, or `None` to join the fullest other room
+  ///   still in its lobby, creating a new room if they're all full.
+  /// # Returns
+  /// The ID of the room joined, or why the client couldn't join.
+  pub fn join(&mut self, client_id: usize, current: Option<u32>, room_id: Option<u32>)
+      -> Result<u32, &'static str> {
+    let id = match room_id {
+      Some(id) => {
+        let room = self.get(id).ok_or("that room doesn't exist")?;
+        if current == Some(id) { return Err("you're already in that room"); }
+        if room.state != RoomState::Lobby { return Err("that room's match has already started"); }
+        if room.is_full() { return Err("that room is full"); }
+        id
+      }
+      None => match self.quick_match(current) {
+        Some(id) => id,
+        None => self.create(client_id).ok_or("the server is full")?,
+      },
+    };
+    if let Some(current) = current { self.leave(client_id, current); }
+    let room = self.get_mut(id).unwrap();
+    room.add_player(client_id);
+    println!("Client {} joined room {}", client_id, room.id);
+    Ok(room.id)
+  }
+
+  /// Remove a client from a room, deleting the room once it's empty.
+  /// # Returns
+  /// The room, if it still has players.
+  pub fn leave(&mut self, client_id: usize, room_id: u32) -> Option<&mut Room> {
+    let index = self.rooms.iter().position(|r| r.id == room_id)?;
+    self.rooms[index].remove_player(client_id);
+    println!("Client {} left room {}", client_id, room_id);
+    if self.rooms[index].players.is_empty() {
+      println!("Closing empty room {}", room_id);
+      self.rooms.remove(index);
+      return None;
+    }
+    Some(&mut self.rooms[index])
+  }
+
+  /// List every room for a client to choose from.
+  pub fn list(&self) -> GameListPacket {
+    GameListPacket { rooms: self.rooms.iter().map(Room::info).collect() }
+  }
+
+  /// Find the fullest room which is still in its lobby and has space, other
+  /// than the room the client is already in.
+  fn quick_match(&self, current: Option<u32>) -> Option<u32> {
+    self.rooms.iter()
+      .filter(|r| r.state == RoomState::Lobby && !r.is_full() && Some(r.id) != current)
+      .max_by_key(|r| r.players.len())
+      .map(|r| r.id)
+  }
+
+  /// Create an empty room, unless there are already too many.
+  /// # Returns
+  /// The ID of the new room.
+  fn create(&mut self, host: usize) -> Option<u32> {
+    if self.rooms.len() >= MAX_ROOMS { return None; }
+    let id = self.next_id;
+    self.next_id += 1;
+    println!("Opening room {}", id);
+    self.rooms.push(Room::new(id, host, self.max_rewind));
+    Some(id)
+  }
+}
+
+impl Default for Rooms {
+  fn default() -> Rooms {
+    Rooms::new(DEFAULT_MAX_REWIND_TICKS)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use common::net::{INPUT_LEFT, INPUT_RIGHT, INPUT_JUMP};
+
+  /// A room with two players whose match has just started.
+  fn started_room(max_rewind: u32) -> Room {
+    let mut room = Room::new(1, 10, max_rewind);
+    for &id in &[10, 11] {
+      room.add_player(id);
+      room.set_ready(id, true).unwrap();
+    }
+    assert!(room.try_start(Instant::now()));
+    room
+  }
+
+  fn step_to(room: &mut Room, tick: u32) {
+    while room.tick < tick { room.step(); }
+  }
+
+  /// Player 11 always sends its inputs on time.
+  fn send_other_inputs(room: &mut Room) {
+    if room.tick == 2 { room.receive_input(11, InputPacket::new(3, INPUT_LEFT | INPUT_JUMP)); }
+    if room.tick == 9 { room.receive_input(11, InputPacket::new(10, 0)); }
+  }
+
+  fn run(room: &mut Room, until: u32) {
+    while room.tick < until {
+      send_other_inputs(room);
+      room.step();
+    }
+  }
+
+  #[test]
+  fn late_input_matches_on_time_input() {
+    let mut on_time = started_room(DEFAULT_MAX_REWIND_TICKS);
+    run(&mut on_time, 4);
+    on_time.receive_input(10, InputPacket::new(5, INPUT_RIGHT));
+    run(&mut on_time, 30);
+
+    let mut late = started_room(DEFAULT_MAX_REWIND_TICKS);
+    run(&mut late, 8);
+    late.receive_input(10, InputPacket::new(5, INPUT_RIGHT));
+    run(&mut late, 30);
+
+    assert_eq!(on_time.entities, late.entities);
+    // The input actually moved the player
+    let start = started_room(DEFAULT_MAX_REWIND_TICKS).entities;
+    assert!(late.entities[1].object.aabb[0] > start[1].object.aabb[0]);
+  }
+
+  #[test]
+  fn old_input_is_clamped_to_the_rewind_limit() {
+    let mut late = started_room(6);
+    step_to(&mut late, 20);
+    late.receive_input(10, InputPacket::new(2, INPUT_RIGHT));
+    step_to(&mut late, 30);
+
+    // The world could only be rewound far enough to resimulate ticks 15 to 20
+    let mut clamped = started_room(6);
+    step_to(&mut clamped, 14);
+    clamped.receive_input(10, InputPacket::new(15, INPUT_RIGHT));
+    step_to(&mut clamped, 30);
+
+    assert_eq!(late.entities, clamped.entities);
+    // The client is still acknowledged with the tick it sent
+    assert_eq!(late.snapshot().acks, vec![InputAck { client_id: 10, tick: 2 }]);
+  }
+
+  #[test]
+  fn rewinding_doesnt_bring_back_a_player_who_left() {
+    let mut room = started_room(DEFAULT_MAX_REWIND_TICKS);
+    step_to(&mut room, 10);
+    room.remove_player(11);
+    room.receive_input(10, InputPacket::new(8, INPUT_RIGHT));
+    step_to(&mut room, 12);
+    assert!(room.entities.iter().all(|e| e.owner != Some(11)));
+    assert!(room.snapshot().entities.iter().all(|e| e.id != 11));
+  }
+
+  #[test]
+  fn out_of_order_inputs_are_ignored() {
+    let mut room = started_room(DEFAULT_MAX_REWIND_TICKS);
+    step_to(&mut room, 10);
+    room.receive_input(10, InputPacket::new(9, INPUT_RIGHT));
+    room.receive_input(10, InputPacket::new(8, INPUT_LEFT));
+    let input = room.history.as_ref().unwrap().input_at(10, 10).cloned();
+    assert_eq!(input, Some(InputPacket::new(9, INPUT_RIGHT)));
+  }
+
+  #[test]
+  fn rejected_join_leaves_the_client_where_it_was() {
+    let mut rooms = Rooms::default();
+    let first = rooms.join(1, None, None).unwrap();
+    assert_eq!(rooms.join(2, None, Some(99)), Err("that room doesn't exist"));
+
+    // Rejoining its own room doesn't close it
+    assert!(rooms.join(1, Some(first), Some(first)).is_err());
+    assert_eq!(rooms.get(first).unwrap().players, vec![1]);
+
+    rooms.get_mut(first).unwrap().settings.max_players = 1;
+    let second = rooms.join(2, None, None).unwrap();
+    assert_ne!(first, second);
+    assert_eq!(rooms.join(2, Some(second), Some(first)), Err("that room is full"));
+    assert_eq!(rooms.get(second).unwrap().players, vec![2]);
+  }
+
+  #[test]
+  fn join_moves_the_client_between_rooms() {
+    let mut rooms = Rooms::default();
+    let first = rooms.join(1, None, None).unwrap();
+    rooms.get_mut(first).unwrap().settings.max_players = 1;
+    let second = rooms.join(2, None, None).unwrap();
+    rooms.get_mut(first).unwrap().settings.max_players = 2;
+
+    assert_eq!(rooms.join(2, Some(second), Some(first)), Ok(first));
+    assert_eq!(rooms.get(first).unwrap().players, vec![1, 2]);
+    // The room it left was empty, so was closed
+    assert!(rooms.get(second).is_none());
+  }
+
+  #[test]
+  fn rooms_in_game_cant_be_joined() {
+    let mut rooms = Rooms::default();
+    let id = rooms.join(1, None, None).unwrap();
+    let room = rooms.get_mut(id).unwrap();
+    room.set_ready(1, true).unwrap();
+    assert!(room.try_start(Instant::now()));
+
+    assert_eq!(rooms.join(2, None, Some(id)), Err("that room's match has already started"));
+    // Quick match makes a new room instead
+    assert_ne!(rooms.join(2, None, None), Ok(id));
+    assert_eq!(rooms.get(id).unwrap().players, vec![1]);
+  }
+
+  fn settings(max_players: u32, tickrate: u32) -> RoomSettings {
+    RoomSettings { map: "arena".to_owned(), max_players, tickrate }
+  }
+
+  #[test]
+  fn match_starts_once_everyone_is_ready() {
+    let mut rooms = Rooms::default();
+    let id = rooms.join(1, None, None).unwrap();
+    rooms.join(2, None, Some(id)).unwrap();
+    let room = rooms.get_mut(id).unwrap();
+    room.set_ready(1, true).unwrap();
+    assert!(!room.try_start(Instant::now()));
+    room.set_ready(2, true).unwrap();
+    room.set_ready(2, false).unwrap();
+    assert!(!room.try_start(Instant::now()));
+    room.set_ready(2, true).unwrap();
+    assert!(room.try_start(Instant::now()));
+    assert_eq!(room.state, RoomState::InGame);
+    // One ground and a player each
+    assert_eq!(room.entities.len(), 3);
+    assert!(room.set_ready(1, false).is_err());
+  }
+
+  #[test]
+  fn match_starts_when_the_last_unready_player_leaves() {
+    let mut rooms = Rooms::default();
+    let id = rooms.join(1, None, None).unwrap();
+    rooms.join(2, None, Some(id)).unwrap();
+    rooms.get_mut(id).unwrap().set_ready(1, true).unwrap();
+    let room = rooms.leave(2, id).unwrap();
+    assert!(room.try_start(Instant::now()));
+  }
+
+  #[test]
+  fn only_the_host_can_change_settings() {
+    let mut rooms = Rooms::default();
+    let id = rooms.join(1, None, None).unwrap();
+    rooms.join(2, None, Some(id)).unwrap();
+    let room = rooms.get_mut(id).unwrap();
+    assert_eq!(room.host, 1);
+    assert!(room.change_settings(2, settings(4, 30)).is_err());
+
+    room.set_ready(2, true).unwrap();
+    room.change_settings(1, settings(4, 30)).unwrap();
+    assert_eq!(room.settings, settings(4, 30));
+    // Everyone has to ready up again for the new settings
+    assert!(room.ready.is_empty());
+  }
+
+  #[test]
+  fn invalid_settings_are_rejected() {
+    let mut rooms = Rooms::default();
+    let id = rooms.join(1, None, None).unwrap();
+    rooms.join(2, None, Some(id)).unwrap();
+    let room = rooms.get_mut(id).unwrap();
+    assert!(room.change_settings(1, settings(1, 60)).is_err());
+    assert!(room.change_settings(1, settings(MAX_PLAYERS_LIMIT as u32 + 1, 60)).is_err());
+    assert!(room.change_settings(1, settings(4, MIN_TICKRATE - 1)).is_err());
+    assert!(room.change_settings(1, settings(4, MAX_TICKRATE + 1)).is_err());
+    let mut long_map = settings(4, 60);
+    long_map.map = "m".repeat(MAX_MAP_NAME_LEN + 1);
+    assert!(room.change_settings(1, long_map).is_err());
+    assert_eq!(room.settings.tickrate, DEFAULT_TICKRATE);
+  }
+
+  #[test]
+  fn hosting_passes_to_the_longest_standing_player() {
+    let mut rooms = Rooms::default();
+    let id = rooms.join(1, None, None).unwrap();
+    rooms.join(2, None, Some(id)).unwrap();
+    rooms.join(3, None, Some(id)).unwrap();
+    assert_eq!(rooms.leave(1, id).unwrap().host, 2);
+    // A player who isn't the host leaving changes nothing
+    rooms.join(4, None, Some(id)).unwrap();
+    assert_eq!(rooms.leave(3, id).unwrap().host, 2);
+    assert_eq!(rooms.leave(2, id).unwrap().host, 4);
+    assert!(rooms.leave(4, id).is_none());
+    assert!(rooms.get(id).is_none());
+  }
+}
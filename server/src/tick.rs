@@ -0,0 +1,127 @@
+//! Scheduling of game ticks. Each match is simulated in fixed steps at the
+//! game tickrate, and the world is sent to clients at the slower comm
+//! tickrate (see netcode_notes.txt). Ticks are scheduled against the clock
+//! rather than counted per loop, so a slow loop runs several ticks at once to
+//! catch up.
+
+use std::time::{Duration, Instant};
+
+/// The rate snapshots are sent to clients, per second.
+pub const COMM_TICKRATE : u32 = 20;
+/// The most ticks run at once to catch up. If a room falls further behind
+/// than this, the rest are skipped rather than trying to catch up forever.
+pub const MAX_CATCH_UP_TICKS : u32 = 15;
+
+/// Decides when a room's ticks are due.
+#[derive(Debug, Clone)]
+pub struct TickScheduler {
+  tickrate: u32,
+  tick_len: Duration,
+  /// When the next tick is due.
+  next_tick: Instant,
+}
+
+impl TickScheduler {
+  /// Create a scheduler with its first tick due now.
+  /// # Params
+  /// * `tickrate` - The game tickrate, in ticks per second.
+  /// * `now` - The current time.
+  pub fn new(tickrate: u32, now: Instant) -> TickScheduler {
+    TickScheduler {
+      tickrate,
+      tick_len: Duration::from_secs(1) / tickrate,
+      next_tick: now,
+    }
+  }
+
+  /// How long until the next tick is due, to wait for in `poll`.
+  pub fn time_until_tick(&self, now: Instant) -> Duration {
+    self.next_tick.saturating_duration_since(now)
+  }
+
+  /// Take the ticks due by now, scheduling the next.
+  /// # Returns
+  /// The number of ticks to run, at most `MAX_CATCH_UP_TICKS`.
+  pub fn due_ticks(&mut self, now: Instant) -> u32 {
+    let mut ticks = 0;
+    while self.next_tick <= now {
+      if ticks == MAX_CATCH_UP_TICKS {
+        let behind = now.duration_since(self.next_tick);
+        println!("Skipping {:?} of ticks to catch up", behind);
+        self.next_tick = now + self.tick_len;
+        break;
+      }
+      ticks += 1;
+      self.next_tick += self.tick_len;
+    }
+    ticks
+  }
+
+  /// Whether a snapshot should be sent after a tick. Snapshots are spread
+  /// evenly over the ticks, so at 60 ticks per second every third tick is a
+  /// comm tick.
+  pub fn is_comm_tick(&self, tick: u32) -> bool {
+    let comm_tick = |tick: u32| tick as u64 * COMM_TICKRATE as u64 / self.tickrate as u64;
+    tick == 0 || comm_tick(tick) != comm_tick(tick - 1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+  use super::*;
+
+  fn tick_len() -> Duration {
+    Duration::from_secs(1) / 60
+  }
+
+  #[test]
+  fn comm_ticks_every_third_tick_at_60() {
+    let scheduler = TickScheduler::new(60, Instant::now());
+    let comm : Vec<u32> = (0..12).filter(|&t| scheduler.is_comm_tick(t)).collect();
+    assert_eq!(comm, vec![0, 3, 6, 9]);
+    assert_eq!((0..60).filter(|&t| scheduler.is_comm_tick(t)).count(), COMM_TICKRATE as usize);
+  }
+
+  #[test]
+  fn comm_ticks_every_tick_at_20() {
+    let scheduler = TickScheduler::new(20, Instant::now());
+    assert!((0..40).all(|t| scheduler.is_comm_tick(t)));
+  }
+
+  #[test]
+  fn one_tick_per_tick_len() {
+    let start = Instant::now();
+    let mut scheduler = TickScheduler::new(60, start);
+    let tick_len = tick_len();
+    assert_eq!(scheduler.due_ticks(start), 1);
+    assert_eq!(scheduler.due_ticks(start), 0);
+    assert_eq!(scheduler.time_until_tick(start), tick_len);
+    assert_eq!(scheduler.due_ticks(start + tick_len / 2), 0);
+    assert_eq!(scheduler.due_ticks(start + tick_len), 1);
+  }
+
+  #[test]
+  fn catches_up_after_a_stall() {
+    let start = Instant::now();
+    let mut scheduler = TickScheduler::new(60, start);
+    let tick_len = tick_len();
+    assert_eq!(scheduler.due_ticks(start), 1);
+    // Stall for 5 ticks, then run the ticks missed along with the one now due
+    assert_eq!(scheduler.due_ticks(start + tick_len * 6), 6);
+    assert_eq!(scheduler.time_until_tick(start + tick_len * 6), tick_len);
+  }
+
+  #[test]
+  fn catch_up_is_capped() {
+    let start = Instant::now();
+    let mut scheduler = TickScheduler::new(60, start);
+    let tick_len = tick_len();
+    let now = start + Duration::from_secs(10);
+    assert_eq!(scheduler.due_ticks(now), MAX_CATCH_UP_TICKS);
+    // The rest are skipped, rather than run on the next loops
+    assert_eq!(scheduler.due_ticks(now), 0);
+    assert_eq!(scheduler.time_until_tick(now), tick_len);
+    assert_eq!(scheduler.due_ticks(now + tick_len), 1);
+  }
+}